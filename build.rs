@@ -0,0 +1,40 @@
+use chrono::{DateTime, Utc};
+use std::process::Command;
+
+/// Embeds the commit, left empty when built outside a git checkout, and the build date for
+/// `--version`. The date is that of `SOURCE_DATE_EPOCH` if set, for reproducible builds.
+fn main() {
+    let commit = git(&["log", "-1", "--format=%h"]).unwrap_or_default();
+    println!("cargo:rustc-env=UTATEN_EPUB_COMMIT={}", commit);
+    println!("cargo:rustc-env=UTATEN_EPUB_BUILD_DATE={}", build_date());
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // Rebuilding the changed sources is building them anew
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        if let Some(head) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}/{}", git_dir, head);
+        }
+    }
+}
+
+fn build_date() -> String {
+    let epoch = std::env::var("SOURCE_DATE_EPOCH").ok().map(|epoch| {
+        let seconds = epoch
+            .trim()
+            .parse()
+            .expect("SOURCE_DATE_EPOCH should be seconds since the epoch");
+        DateTime::from_timestamp(seconds, 0).expect("SOURCE_DATE_EPOCH should be a valid date")
+    });
+    epoch
+        .unwrap_or_else(Utc::now)
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    let text = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !text.trim().is_empty()).then(|| text.trim().to_string())
+}
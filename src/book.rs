@@ -0,0 +1,368 @@
+//! Putting the cached lyric files in the order of the e-book, sorted, shuffled or grouped by
+//! artist, and without the songs having the same lyrics as another.
+
+use crate::extract::{element, extract_lyric, plain_text, select_first};
+use crate::http::write_atomically;
+use crate::songs::{collation_key, sanitize_filename};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use kuchiki::{traits::TendrilSink, NodeRef};
+use log::{debug, info};
+use serde::Serialize;
+use std::collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What order the songs of the e-book are in.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Sort {
+    /// By song title
+    Title,
+    /// By artist name, songs of the same artist by title
+    Artist,
+    /// As listed in the songs file
+    File,
+}
+
+/// A song left out of the e-book for having the same lyrics as an earlier one.
+#[derive(Serialize)]
+pub struct Duplicate {
+    pub song: String,
+    pub duplicate_of: String,
+}
+
+/// Sorts the cached lyric files by title or artist, keeping the songs file order otherwise.
+pub fn sort_lyrics(filenames: Vec<PathBuf>, sort: Sort) -> Result<Vec<PathBuf>> {
+    if sort == Sort::File {
+        return Ok(filenames);
+    }
+    let mut keyed = filenames
+        .into_iter()
+        .map(|filename| {
+            let title = cached_text(&filename, ".newLyricTitle")?.unwrap_or_default();
+            let key = match sort {
+                // Songs of unknown artists come last, like with `--group-by-artist`
+                Sort::Artist => match cached_text(&filename, ".newLyricWork__name")? {
+                    Some(artist) => (false, collation_key(&artist), collation_key(&title)),
+                    None => (true, String::new(), collation_key(&title)),
+                },
+                _ => (false, collation_key(&title), String::new()),
+            };
+            Ok((key, filename))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(keyed.into_iter().map(|(_, filename)| filename).collect())
+}
+
+/// Shuffles the cached lyric files, into the same order every time with the same seed. Without
+/// a seed, the one picked is logged, so the order can be built again.
+pub fn shuffle(filenames: &mut [PathBuf], seed: Option<u64>) {
+    let mut state = seed.unwrap_or_else(|| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let seed = now.as_nanos() as u64;
+        info!("Shuffling the songs with `--seed {}`", seed);
+        seed
+    });
+    // splitmix64, which is random enough for ordering songs
+    let mut next = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    // Fisher-Yates
+    for i in (1..filenames.len()).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        filenames.swap(i, j);
+    }
+}
+
+/// Returns the trimmed text of the first element matching `selector` in the cached lyric
+/// file, if there is one and it is not empty.
+pub fn cached_text(filename: &Path, selector: &str) -> Result<Option<String>> {
+    let document = kuchiki::parse_html().one(fs::read_to_string(filename)?);
+    Ok(document
+        .select_first(selector)
+        .map(|element| element.text_contents().trim().to_string())
+        .ok()
+        .filter(|text| !text.is_empty()))
+}
+
+/// Returns the artist of the lyric files to credit as the author of the e-book, Various
+/// Artists if they have several.
+pub fn songs_author(filenames: &[PathBuf]) -> Result<Option<String>> {
+    let mut artists = BTreeSet::new();
+    for filename in filenames {
+        // Not the index and the artist sections, whose artists are in headings
+        if let Some(artist) = cached_text(filename, ".newLyricWork__name")? {
+            artists.insert(artist);
+        }
+    }
+    Ok(match artists.len() {
+        0 => None,
+        1 => artists.pop_first(),
+        _ => Some("Various Artists".to_string()),
+    })
+}
+
+/// Groups the cached lyric files by artist in alphabetical order, each group preceded by a
+/// section page with the artist's name as heading. Songs keep their order within a group and
+/// songs of unknown artists come last.
+pub fn group_by_artist(lyrics_dir: &Path, filenames: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let groups = artist_groups(filenames)?;
+    let sections_dir = lyrics_dir.join(".artists");
+    fs::create_dir_all(&sections_dir)?;
+    let mut grouped = Vec::new();
+    for (artist, filenames) in groups {
+        let section = sections_dir.join(format!("{}.html", sanitize_filename(&artist)));
+        let heading = element("h1", "artistHeading");
+        heading.append(NodeRef::new_text(artist));
+        write_atomically(
+            &section,
+            format!("{}<div class=\"page-break\"></div>", heading.to_string()),
+        )?;
+        grouped.push(section);
+        grouped.extend(filenames);
+    }
+    Ok(grouped)
+}
+
+/// Groups the lyric files by artist, in the order of the artists' names and then the songs'
+/// order. Songs of unknown artists are grouped last, under "Unknown".
+pub fn artist_groups(filenames: &[PathBuf]) -> Result<Vec<(String, Vec<PathBuf>)>> {
+    let mut groups = BTreeMap::<_, Vec<_>>::new();
+    for filename in filenames {
+        let artist = cached_text(filename, ".newLyricWork__name")?;
+        groups.entry(artist).or_default().push(filename.clone());
+    }
+    // `None` sorts first, but unknown artists belong at the end
+    let unknown = groups.remove(&None);
+    Ok(groups
+        .into_iter()
+        .map(|(artist, filenames)| (artist.unwrap(), filenames))
+        .chain(unknown.map(|filenames| ("Unknown".to_string(), filenames)))
+        .collect())
+}
+
+/// Writes the lyrics of the cached lyric files into a JSON array.
+pub fn export_json(path: &Path, filenames: &[PathBuf]) -> Result<()> {
+    let lyrics = filenames
+        .iter()
+        .map(|filename| {
+            let document = kuchiki::parse_html().one(fs::read_to_string(filename)?);
+            extract_lyric(&document).with_context(|| filename.display().to_string())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(write_atomically(
+        path,
+        serde_json::to_string_pretty(&lyrics)?,
+    )?)
+}
+
+/// Leaves out the lyric files of the songs with the same lyrics as an earlier one, whatever
+/// their whitespace and furigana.
+pub fn dedupe_content(
+    songs: &[String],
+    filenames: Vec<PathBuf>,
+) -> Result<(Vec<PathBuf>, Vec<Duplicate>)> {
+    let mut seen = HashMap::<_, &String>::new();
+    let mut kept = Vec::new();
+    let mut duplicates = Vec::new();
+    for (song, filename) in songs.iter().zip(filenames) {
+        let document = kuchiki::parse_html().one(fs::read_to_string(&filename)?);
+        let lyric_body = select_first(&document, ".lyricBody")
+            .with_context(|| filename.display().to_string())?;
+        let mut hasher = DefaultHasher::new();
+        plain_text(&lyric_body)
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .for_each(|c| c.hash(&mut hasher));
+        match seen.entry(hasher.finish()) {
+            Entry::Occupied(first) => {
+                debug!("{} has the same lyrics as {}", song, first.get());
+                duplicates.push(Duplicate {
+                    song: song.clone(),
+                    duplicate_of: first.get().to_string(),
+                });
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(song);
+                kept.push(filename);
+            }
+        }
+    }
+    Ok((kept, duplicates))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a lyric file like `Downloader::save_lyric` does, without the credits.
+    fn lyric_file(
+        dir: &Path,
+        name: &str,
+        title: &str,
+        artist: Option<&str>,
+        lyrics: &str,
+    ) -> PathBuf {
+        let artist = artist
+            .map(|artist| {
+                format!(
+                    "<dl class=\"newLyricWork\"><dt class=\"newLyricWork__name\"><a>{}</a></dt></dl>",
+                    artist
+                )
+            })
+            .unwrap_or_default();
+        let html = format!(
+            "<!DOCTYPE html>\n<html lang=\"ja\"><body><article class=\"lyricDetail\">\
+             <h2 class=\"newLyricTitle\">{}</h2><div class=\"lyricData\">{}</div>\
+             <div class=\"lyricBody\"><div class=\"medium\">{}</div></div></article></body></html>",
+            title, artist, lyrics
+        );
+        let filename = dir.join(format!("{}.html", name));
+        fs::write(&filename, html).unwrap();
+        filename
+    }
+
+    fn names(filenames: &[PathBuf]) -> Vec<&str> {
+        filenames
+            .iter()
+            .map(|filename| filename.file_stem().unwrap().to_str().unwrap())
+            .collect()
+    }
+
+    fn songs(dir: &Path) -> Vec<PathBuf> {
+        vec![
+            lyric_file(dir, "c", "Cloudy", Some("DOES"), "曇天"),
+            lyric_file(dir, "a", "Bell", None, "鐘"),
+            lyric_file(dir, "b", "Armor", Some("Aqua Timez"), "修羅"),
+        ]
+    }
+
+    #[test]
+    fn sort_lyrics_by_title_or_artist() {
+        let dir = tempfile::tempdir().unwrap();
+        let songs = songs(dir.path());
+
+        let sorted = sort_lyrics(songs.clone(), Sort::Title).unwrap();
+        assert_eq!(names(&sorted), ["b", "a", "c"]);
+        // Songs of unknown artists come last
+        let sorted = sort_lyrics(songs.clone(), Sort::Artist).unwrap();
+        assert_eq!(names(&sorted), ["b", "c", "a"]);
+        let sorted = sort_lyrics(songs, Sort::File).unwrap();
+        assert_eq!(names(&sorted), ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn shuffle_is_the_same_with_the_same_seed() {
+        let filenames = (0..20)
+            .map(|i| PathBuf::from(format!("{}.html", i)))
+            .collect::<Vec<_>>();
+        let mut shuffled = filenames.clone();
+        shuffle(&mut shuffled, Some(42));
+        let mut again = filenames.clone();
+        shuffle(&mut again, Some(42));
+        assert_eq!(shuffled, again);
+        assert_ne!(shuffled, filenames);
+
+        shuffled.sort();
+        let mut sorted = filenames;
+        sorted.sort();
+        assert_eq!(shuffled, sorted);
+    }
+
+    #[test]
+    fn artist_groups_put_unknown_artists_last() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut songs = songs(dir.path());
+        songs.push(lyric_file(dir.path(), "d", "Dawn", Some("DOES"), "夜明け"));
+
+        let groups = artist_groups(&songs).unwrap();
+        let groups = groups
+            .iter()
+            .map(|(artist, filenames)| (artist.as_str(), names(filenames)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            [
+                ("Aqua Timez", vec!["b"]),
+                ("DOES", vec!["c", "d"]),
+                ("Unknown", vec!["a"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn group_by_artist_adds_a_section_per_artist() {
+        let dir = tempfile::tempdir().unwrap();
+        let grouped = group_by_artist(dir.path(), &songs(dir.path())).unwrap();
+        assert_eq!(
+            names(&grouped),
+            ["Aqua Timez", "b", "DOES", "c", "Unknown", "a"]
+        );
+        let section = fs::read_to_string(&grouped[2]).unwrap();
+        assert!(section.contains("<h1 class=\"artistHeading\">DOES</h1>"));
+    }
+
+    #[test]
+    fn songs_author_is_the_only_artist_or_various() {
+        let dir = tempfile::tempdir().unwrap();
+        let songs = songs(dir.path());
+        assert_eq!(songs_author(&songs[..2]).unwrap().as_deref(), Some("DOES"));
+        assert_eq!(
+            songs_author(&songs).unwrap().as_deref(),
+            Some("Various Artists")
+        );
+        assert_eq!(songs_author(&songs[1..2]).unwrap(), None);
+    }
+
+    #[test]
+    fn dedupe_content_ignores_whitespace_and_furigana() {
+        let dir = tempfile::tempdir().unwrap();
+        let filenames = vec![
+            lyric_file(
+                dir.path(),
+                "a",
+                "曇天",
+                Some("DOES"),
+                "曇天の空に<br>鐘が鳴る",
+            ),
+            lyric_file(
+                dir.path(),
+                "b",
+                "曇天 (TV size)",
+                Some("DOES"),
+                "<ruby>曇天<rt>どんてん</rt></ruby>の 空に<br><br>鐘が鳴る",
+            ),
+            lyric_file(dir.path(), "c", "修羅", Some("DOES"), "修羅"),
+        ];
+        let songs = ["曇天 / DOES", "曇天 (TV size) / DOES", "修羅 / DOES"].map(String::from);
+
+        let (kept, duplicates) = dedupe_content(&songs, filenames).unwrap();
+        assert_eq!(names(&kept), ["a", "c"]);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].song, "曇天 (TV size) / DOES");
+        assert_eq!(duplicates[0].duplicate_of, "曇天 / DOES");
+    }
+
+    #[test]
+    fn export_json_lists_the_lyrics() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lyrics.json");
+        export_json(&path, &songs(dir.path())[..2]).unwrap();
+
+        let json =
+            serde_json::from_str::<serde_json::Value>(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(json[0]["title"], "Cloudy");
+        assert_eq!(json[0]["artist"], "DOES");
+        assert_eq!(json[0]["lyrics"], "曇天");
+        assert_eq!(json[1]["artist"], "");
+    }
+}
@@ -0,0 +1,128 @@
+//! Generating the cover of the e-book when `--cover` doesn't give one.
+
+use anyhow::{anyhow, Result};
+use resvg::{tiny_skia, usvg};
+
+const WIDTH: u32 = 1200;
+const HEIGHT: u32 = 1800;
+const MARGIN: u32 = 120;
+
+/// Fonts preferred for the cover, before any other installed one.
+const FONT_FAMILIES: &[&str] = &[
+    "Noto Sans CJK JP",
+    "Noto Sans JP",
+    "Hiragino Sans",
+    "Yu Gothic",
+    "Meiryo",
+    "IPAexGothic",
+];
+
+/// Most lines of title on the cover, wrapped titles longer than that are cut off.
+const MAX_LINES: usize = 6;
+
+/// Renders a PNG cover with the title and author on a colored background, in whatever
+/// Japanese capable font the system has.
+pub fn generate(title: &str, author: Option<&str>) -> Result<Vec<u8>> {
+    // Long titles get smaller type rather than ever more lines
+    let (font_size, mut lines) = [120, 100, 84, 72, 60]
+        .iter()
+        .map(|&size| (size, wrap(title, (WIDTH - 2 * MARGIN) as f32 / size as f32)))
+        .find(|(_, lines)| lines.len() <= MAX_LINES)
+        .unwrap_or_else(|| (60, wrap(title, (WIDTH - 2 * MARGIN) as f32 / 60.0)));
+    if lines.len() > MAX_LINES {
+        lines.truncate(MAX_LINES);
+        lines[MAX_LINES - 1].push('…');
+    }
+
+    let line_height = font_size as f32 * 1.3;
+    let top = HEIGHT as f32 * 0.4 - line_height * (lines.len() - 1) as f32 / 2.0;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{WIDTH}\" height=\"{HEIGHT}\">\
+         <rect width=\"100%\" height=\"100%\" fill=\"#2AA29A\"/>\
+         <rect x=\"{MARGIN}\" y=\"{}\" width=\"{}\" height=\"8\" fill=\"#fff\"/>",
+        HEIGHT - MARGIN * 3,
+        WIDTH - 2 * MARGIN
+    );
+    for (i, line) in lines.iter().enumerate() {
+        svg += &text(line, top + line_height * i as f32, font_size);
+    }
+    if let Some(author) = author {
+        svg += &text(author, (HEIGHT - MARGIN * 2) as f32, 56);
+    }
+    svg += "</svg>";
+
+    let mut options = usvg::Options::default();
+    let fonts = options.fontdb_mut();
+    fonts.load_system_fonts();
+    let installed = |family: &str| {
+        fonts
+            .faces()
+            .any(|face| face.families.iter().any(|(name, _)| name == family))
+    };
+    let families = fonts
+        .faces()
+        .filter_map(|face| Some(face.families.first()?.0.clone()))
+        .collect::<Vec<_>>();
+    let family = FONT_FAMILIES
+        .iter()
+        .find(|family| installed(family))
+        .map(|family| family.to_string())
+        .or_else(|| {
+            families
+                .iter()
+                .find(|family| family.contains("Sans"))
+                .cloned()
+        })
+        .or_else(|| families.first().cloned())
+        .ok_or_else(|| anyhow!("no fonts installed"))?;
+    fonts.set_sans_serif_family(family);
+    let tree = usvg::Tree::from_str(&svg, &options)?;
+    let mut pixmap = tiny_skia::Pixmap::new(WIDTH, HEIGHT).unwrap();
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    pixmap
+        .encode_png()
+        .map_err(|err| anyhow!("could not encode the cover: {}", err))
+}
+
+fn text(text: &str, y: f32, font_size: u32) -> String {
+    let escaped = text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    format!(
+        "<text x=\"{}\" y=\"{y}\" font-size=\"{font_size}\" fill=\"#fff\" text-anchor=\"middle\" \
+         font-family=\"sans-serif\">\
+         {escaped}</text>",
+        WIDTH / 2
+    )
+}
+
+/// Wraps the text into lines of at most `width` ems. Japanese breaks between any characters,
+/// other scripts only at spaces unless a word is longer than a line.
+fn wrap(text: &str, width: f32) -> Vec<String> {
+    let em = |c: char| if c.is_ascii() { 0.6 } else { 1.0 };
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut line_width = 0.0;
+    for word in text.split_inclusive(|c: char| c == ' ' || !c.is_ascii()) {
+        let word_width = word.chars().map(em).sum::<f32>();
+        if line_width + word_width.min(width) > width && !line.is_empty() {
+            lines.push(line.trim_end().to_string());
+            line.clear();
+            line_width = 0.0;
+        }
+        for c in word.chars() {
+            if line_width + em(c) > width {
+                lines.push(line.trim_end().to_string());
+                line.clear();
+                line_width = 0.0;
+            }
+            line.push(c);
+            line_width += em(c);
+        }
+    }
+    if !line.trim().is_empty() {
+        lines.push(line.trim_end().to_string());
+    }
+    lines
+}
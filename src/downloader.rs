@@ -0,0 +1,1039 @@
+//! Searching the lyric sites, downloading lyrics and building them into EPUB e-books from
+//! other programs, the way the command line tool does it.
+//!
+//! ```no_run
+//! use std::path::{Path, PathBuf};
+//! use std::time::Duration;
+//! use utaten_epub::downloader::{Downloader, EpubOptions};
+//! use utaten_epub::epub::{Metadata, DEFAULT_CSS};
+//! use utaten_epub::http::{Http, RetryPolicy};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let http = Http::new(RetryPolicy {
+//!     retries: 3,
+//!     delay: Duration::from_millis(500),
+//! });
+//! let downloader = Downloader::new(http);
+//! let Some(url) = downloader.search("曇天", Some("DOES"))? else {
+//!     return Ok(());
+//! };
+//! let lyric = downloader.download(&url, Path::new("曇天.html"))?;
+//! println!("{} by {}:\n{}", lyric.title, lyric.artist, lyric.lyrics);
+//!
+//! let options = EpubOptions {
+//!     output: Path::new("lyrics.epub"),
+//!     metadata: Metadata {
+//!         title: "Lyrics",
+//!         author: Some(&lyric.artist),
+//!         lang: "ja",
+//!         cover: None,
+//!         date: None,
+//!     },
+//!     css: DEFAULT_CSS,
+//!     fonts: &[],
+//! };
+//! downloader.build_native_epub(&[PathBuf::from("曇天.html")], &options)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::epub::{self, Metadata};
+use crate::error::LyricError;
+use crate::extract::{
+    credits_line, element, extract_credits, extract_lyric, sanitize, select_first, ExtractOptions,
+    Lyric,
+};
+use crate::http::{url_filename, write_atomically, Http, Throttle};
+use crate::songs::{canonical_song, collation_key, normalize_query, split_song};
+use crate::source::{self, LyricSource, SearchResult, Source};
+use anyhow::Result;
+use kuchiki::{traits::TendrilSink, NodeRef};
+use log::{debug, info, trace, warn};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Picks one of the search results for the song, best match first, or `None` to skip the song.
+pub type Chooser = fn(&str, &[(f64, SearchResult)]) -> io::Result<Option<usize>>;
+
+/// Finds songs on the lyric sites and downloads their lyrics into lyric files, ready for
+/// building. Made by `Downloader::builder`, or `Downloader::new` for the defaults.
+pub struct Downloader<'a> {
+    http: Http,
+    /// Waited for before every request to the sites
+    throttle: Throttle,
+    /// Sites to search, in order, and to download the lyric URLs of
+    sources: Vec<&'a dyn LyricSource>,
+    options: ExtractOptions,
+    /// Least similarity from 0 to 1 of the best search result to the song for finding it
+    match_threshold: f64,
+    /// Most pages of search results to look through for the song
+    max_pages: usize,
+    /// Search for the song by its NFKC form with its whitespace collapsed
+    normalize_query: bool,
+    /// Asks which search result to download when there are several, instead of taking the
+    /// best match
+    choose: Option<Chooser>,
+    /// Where the lyric pages are cached as downloaded, to extract them again without
+    /// downloading them, or revalidate them with the site
+    raw_dir: Option<PathBuf>,
+    /// Cache the pages downloaded into `raw_dir`, which is only read otherwise
+    cache_raw: bool,
+    /// URLs of the lyric pages found for the songs, not to search them again, see
+    /// `read_searches`
+    searches: Mutex<BTreeMap<String, String>>,
+    /// Search the songs even when their lyric pages were found before
+    refresh_search: bool,
+    /// Sites to find translations of the lyrics on, in order, to add after the lyrics
+    translations: Vec<&'a dyn LyricSource>,
+}
+
+/// How `Downloader::build_native_epub` builds the e-book.
+pub struct EpubOptions<'a> {
+    pub output: &'a Path,
+    pub metadata: Metadata<'a>,
+    /// The stylesheet, like `epub::DEFAULT_CSS`
+    pub css: &'a str,
+    /// Fonts to embed, which the stylesheet refers to under `/fonts/`
+    pub fonts: &'a [PathBuf],
+}
+
+/// Validators of a cached lyric page, sent back to download it again only if it changed.
+#[derive(Default, Serialize, Deserialize)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Sets up a `Downloader`, starting from the defaults of `Downloader::new`.
+pub struct DownloaderBuilder<'a> {
+    downloader: Downloader<'a>,
+}
+
+impl<'a> DownloaderBuilder<'a> {
+    /// Waits `delay` between requests to the sites.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.downloader.throttle = Throttle::new(delay);
+        self
+    }
+
+    /// Searches the sites in order, and downloads the lyric URLs of them.
+    pub fn sources(mut self, sources: Vec<&'a dyn LyricSource>) -> Self {
+        self.downloader.sources = sources;
+        self
+    }
+
+    /// Adds the first translation of the lyrics these sites have after them.
+    pub fn translations(mut self, translations: Vec<&'a dyn LyricSource>) -> Self {
+        self.downloader.translations = translations;
+        self
+    }
+
+    pub fn options(mut self, options: ExtractOptions) -> Self {
+        self.downloader.options = options;
+        self
+    }
+
+    /// Least similarity from 0 to 1 of the best search result to the song for finding it.
+    pub fn match_threshold(mut self, match_threshold: f64) -> Self {
+        self.downloader.match_threshold = match_threshold;
+        self
+    }
+
+    /// Looks through up to `max_pages` pages of search results for the song.
+    pub fn max_pages(mut self, max_pages: usize) -> Self {
+        self.downloader.max_pages = max_pages;
+        self
+    }
+
+    /// Whether to search for the song by its NFKC form with its whitespace collapsed.
+    pub fn normalize_query(mut self, normalize_query: bool) -> Self {
+        self.downloader.normalize_query = normalize_query;
+        self
+    }
+
+    /// Asks which search result to download when there are several, instead of taking the
+    /// best match.
+    pub fn choose(mut self, choose: Option<Chooser>) -> Self {
+        self.downloader.choose = choose;
+        self
+    }
+
+    /// Extracts the lyric pages cached in `raw_dir` again instead of downloading them, or
+    /// revalidates them with the site, caching the ones downloaded there too if `cache_raw`.
+    pub fn raw_dir(mut self, raw_dir: PathBuf, cache_raw: bool) -> Self {
+        self.downloader.raw_dir = Some(raw_dir);
+        self.downloader.cache_raw = cache_raw;
+        self
+    }
+
+    /// Takes the lyric URLs found for the songs before instead of searching them again, like
+    /// the ones `Downloader::read_searches` reads.
+    pub fn searches(mut self, searches: BTreeMap<String, String>) -> Self {
+        self.downloader.searches = Mutex::new(searches);
+        self
+    }
+
+    /// Searches the songs even when their lyric pages were found before.
+    pub fn refresh_search(mut self, refresh_search: bool) -> Self {
+        self.downloader.refresh_search = refresh_search;
+        self
+    }
+
+    pub fn build(self) -> Downloader<'a> {
+        self.downloader
+    }
+}
+
+impl<'a> Downloader<'a> {
+    /// Downloads from utaten with the defaults of the command line tool, without caching.
+    pub fn new(http: Http) -> Self {
+        Downloader {
+            http,
+            throttle: Throttle::new(Duration::from_millis(500)),
+            sources: vec![Source::Utaten.backend()],
+            options: ExtractOptions::default(),
+            match_threshold: 0.6,
+            max_pages: 3,
+            normalize_query: true,
+            choose: None,
+            raw_dir: None,
+            cache_raw: false,
+            searches: Mutex::default(),
+            refresh_search: false,
+            translations: Vec::new(),
+        }
+    }
+
+    /// Sets up a downloader other than the default one of `new`.
+    pub fn builder(http: Http) -> DownloaderBuilder<'a> {
+        DownloaderBuilder {
+            downloader: Downloader::new(http),
+        }
+    }
+
+    /// The sites searched, in order.
+    pub fn sources(&self) -> &[&'a dyn LyricSource] {
+        &self.sources
+    }
+
+    /// Searches the sites in order for the song, returning the lyric URL of the best match
+    /// that's similar enough, or the one found before.
+    pub fn search(&self, title: &str, artist: Option<&str>) -> Result<Option<String>, LyricError> {
+        // A bare trailing slash keeps slashes of the title from counting as the separator
+        let song = canonical_song(&format!("{} / {}", title, artist.unwrap_or_default()));
+        if let Some((url, _)) = self.found_before(&song) {
+            return Ok(Some(url));
+        }
+        self.throttle.wait();
+        let Some((_, url)) = self.find_song(&song)? else {
+            return Ok(None);
+        };
+        let mut searches = self.searches.lock().unwrap();
+        searches.insert(song, url.clone());
+        Ok(Some(url))
+    }
+
+    /// Downloads the lyric page at `url` and extracts the lyric into `filename`.
+    pub fn download(&self, url: &str, filename: &Path) -> Result<Lyric, LyricError> {
+        let source = self
+            .source_for(url)
+            .ok_or_else(|| LyricError::UnknownUrl(url.to_string()))?;
+        self.throttle.wait();
+        self.download_lyric(source, url, url, filename, false)?;
+        extract_lyric(&kuchiki::parse_html().one(fs::read_to_string(filename)?))
+    }
+
+    /// Builds the lyric files into an EPUB e-book natively, without pandoc, one chapter per
+    /// song.
+    pub fn build_native_epub(&self, lyrics: &[PathBuf], options: &EpubOptions) -> Result<()> {
+        epub::build(
+            options.output,
+            lyrics,
+            &options.metadata,
+            options.css,
+            options.fonts,
+        )
+    }
+
+    /// Searches the lyric sites in order for the song, unless it's a lyric URL or was found
+    /// before, and downloads its lyric into `filename` from the first site that has it.
+    /// Returns whether the song was found.
+    pub fn fetch_song(&self, song: &str, filename: &Path, fresh: bool) -> Result<bool, LyricError> {
+        self.throttle.wait();
+        if let Some(source) = self.source_for(song) {
+            self.download_lyric(source, song, song, filename, fresh)?;
+            return Ok(true);
+        }
+        if let Some((url, source)) = self.found_before(song) {
+            debug!("Found {} before: {}", song, url);
+            match self.download_lyric(source, &url, song, filename, fresh) {
+                // The page is gone, but the song may be found elsewhere
+                Err(LyricError::NotFound) => {
+                    self.searches.lock().unwrap().remove(song);
+                }
+                result => return result.map(|()| true),
+            }
+        }
+        let Some((source, url)) = self.find_song(song)? else {
+            return Ok(false);
+        };
+        self.download_lyric(source, &url, song, filename, fresh)?;
+        let mut searches = self.searches.lock().unwrap();
+        searches.insert(song.to_string(), url);
+        Ok(true)
+    }
+
+    /// Extracts the lyric of the lyric file again from its cached page, if it's cached.
+    /// Returns whether it was extracted again.
+    pub fn refresh_lyric(&self, song: &str, filename: &Path) -> Result<bool, LyricError> {
+        let document = kuchiki::parse_html().one(fs::read_to_string(filename)?);
+        let Some(url) = extract_lyric(&document)?.url else {
+            debug!("Not extracting {} again, its URL is unknown", song);
+            return Ok(false);
+        };
+        let source = self
+            .source_for(&url)
+            .ok_or_else(|| LyricError::UnknownUrl(url.clone()))?;
+        let Some(page) = self
+            .raw_page(&url)
+            .and_then(|raw| fs::read_to_string(raw).ok())
+        else {
+            debug!("Not extracting {} again, its page is not cached", song);
+            return Ok(false);
+        };
+        info!("Extracting lyric for {} again", song);
+        // Kept rather than searched again, the lyric is only extracted again
+        let translation = select_first(&document, ".lyricTranslation").ok();
+        self.write_lyric(source, &url, &page, filename, translation)?;
+        Ok(true)
+    }
+
+    /// Searches the site for the song like `title / artist`, returning the results by how
+    /// similar they are to it, best first.
+    pub fn ranked_results(
+        &self,
+        source: &dyn LyricSource,
+        song: &str,
+    ) -> Result<Vec<(f64, SearchResult)>, LyricError> {
+        let query = if self.normalize_query {
+            normalize_query(song)
+        } else {
+            song.to_string()
+        };
+        let results = source.search(&query, &self.http, &self.throttle, self.max_pages)?;
+        Ok(rank_results(song, results))
+    }
+
+    /// The site of the lyric URL, one of the sources or else any site the URL is of.
+    pub fn source_for(&self, url: &str) -> Option<&'a dyn LyricSource> {
+        self.sources
+            .iter()
+            .copied()
+            .find(|source| url.starts_with(&source.lyric_url_prefix()))
+            .or_else(|| source::for_url(url))
+    }
+
+    /// Where the page at `url` is cached, if pages are.
+    pub fn raw_page(&self, url: &str) -> Option<PathBuf> {
+        let raw_dir = self.raw_dir.as_ref()?;
+        Some(raw_dir.join(url_filename(url)))
+    }
+
+    /// Lists the songs of the artist on the site, up to `limit` of them.
+    pub fn discography(
+        &self,
+        source: &dyn LyricSource,
+        artist: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResult>, LyricError> {
+        source.discography(artist, &self.http, &self.throttle, limit)
+    }
+
+    /// The lyric URL found for the song before, even when searching again.
+    pub fn searched(&self, song: &str) -> Option<String> {
+        self.searches.lock().unwrap().get(song).cloned()
+    }
+
+    /// Forgets the lyric URLs found for the songs `forget` is true of, given the song and the
+    /// URL, so they're searched again. Returns how many were forgotten.
+    pub fn forget_searches(&self, forget: impl Fn(&str, &str) -> bool) -> usize {
+        let mut searches = self.searches.lock().unwrap();
+        let searched = searches.len();
+        searches.retain(|song, url| !forget(song, url));
+        searched - searches.len()
+    }
+
+    /// Reads the lyric URLs found for the songs saved by `save_searches`, none if there's no
+    /// such file.
+    pub fn read_searches(path: &Path) -> BTreeMap<String, String> {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    /// Saves the lyric URLs found for the songs, to read them back with `read_searches`.
+    pub fn save_searches(&self, path: &Path) -> io::Result<()> {
+        let searches = self.searches.lock().unwrap();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomically(path, serde_json::to_string_pretty(&*searches)?)
+    }
+
+    /// Extracts the lyric from the page at `url` and writes it into `filename`, ready for
+    /// building, followed by its translation if the translation sites have one.
+    pub fn save_lyric(
+        &self,
+        source: &dyn LyricSource,
+        url: &str,
+        page: &str,
+        filename: &Path,
+    ) -> Result<(), LyricError> {
+        self.write_lyric(source, url, page, filename, None)
+    }
+
+    /// Returns the first translation of the song the translation sites have, under a heading.
+    /// Songs without translations are common, so not finding one is only logged.
+    pub fn translation(&self, song: &str) -> Option<NodeRef> {
+        for &source in &self.translations {
+            self.throttle.wait();
+            let found = self.ranked_results(source, song).and_then(|results| {
+                let Some((score, result)) = results.first() else {
+                    return Ok(None);
+                };
+                if *score < self.match_threshold {
+                    debug!(
+                        "Best translation match for {} is too weak: {}",
+                        song, result.url
+                    );
+                    return Ok(None);
+                }
+                self.throttle.wait();
+                source.translation(&result.url, &self.http, &self.throttle)
+            });
+            match found {
+                Ok(Some(lines)) => {
+                    info!("Found a translation of {} on {}", song, source.name());
+                    let translation = element("div", "lyricTranslation");
+                    let heading = element("h3", "lyricTranslation__heading");
+                    heading.append(NodeRef::new_text("Translation"));
+                    translation.append(heading);
+                    translation.append(lines);
+                    return Some(translation);
+                }
+                Ok(None) => debug!("No translation of {} on {}", song, source.name()),
+                Err(err) => debug!("No translation of {} from {}: {}", song, source.name(), err),
+            }
+        }
+        None
+    }
+
+    /// Writes the lyric like `save_lyric`, with the `translation` it had before if any.
+    fn write_lyric(
+        &self,
+        source: &dyn LyricSource,
+        url: &str,
+        page: &str,
+        filename: &Path,
+        translation: Option<NodeRef>,
+    ) -> Result<(), LyricError> {
+        let options = &self.options;
+        let article = source.extract(page, options)?;
+        // Remembered for exporting, the cached file is all that's left of the page
+        let attributes = &article.as_element().unwrap().attributes;
+        attributes.borrow_mut().insert("data-url", url.to_string());
+
+        if options.credits_heading {
+            let credits = credits_line(
+                &extract_credits(&article, "作詞"),
+                &extract_credits(&article, "作曲"),
+            );
+            if let Some(credits) = credits {
+                select_first(&article, ".newLyricTitle")?.insert_after(credits);
+            }
+        }
+        let translation = translation.or_else(|| {
+            if self.translations.is_empty() {
+                return None;
+            }
+            let text_of =
+                |selector| select_first(&article, selector).map(|node| node.text_contents());
+            let title = text_of(".newLyricTitle").ok()?;
+            let artist = text_of(".newLyricWork__name").unwrap_or_default();
+            self.translation(&format!("{} / {}", title.trim(), artist.trim()))
+        });
+        if let Some(translation) = translation {
+            article.append(translation);
+        }
+        article.append(element("div", "page-break"));
+        sanitize(&article);
+
+        let document = format!(
+            "<!DOCTYPE html>\n<html lang=\"{}\">\n<head>\n<meta charset=\"utf-8\">\n</head>\n\
+             <body>\n{}\n</body>\n</html>\n",
+            options.lang,
+            article.to_string()
+        );
+        Ok(write_atomically(filename, document)?)
+    }
+
+    /// The lyric URL found for the song before, unless searching again.
+    fn found_before(&self, song: &str) -> Option<(String, &'a dyn LyricSource)> {
+        if self.refresh_search {
+            return None;
+        }
+        let url = self.searched(song)?;
+        let source = self.source_for(&url)?;
+        Some((url, source))
+    }
+
+    /// Searches the sites in order for the best match of the song, or the one chosen by
+    /// `choose`, returning its site and lyric URL.
+    fn find_song(&self, song: &str) -> Result<Option<(&'a dyn LyricSource, String)>, LyricError> {
+        for &source in &self.sources {
+            let results = self.ranked_results(source, song)?;
+            let Some((best_score, best_result)) = results.first() else {
+                debug!("{} not found on {}", song, source.name());
+                continue;
+            };
+            let chosen = match self.choose {
+                Some(choose) if results.len() > 1 => choose(song, &results)?,
+                _ if *best_score < self.match_threshold => {
+                    warn!(
+                        "Best match for {} on {} is too weak: {} / {} ({:.0}%)",
+                        song,
+                        source.name(),
+                        best_result.title,
+                        best_result.artist,
+                        best_score * 100.0
+                    );
+                    continue;
+                }
+                _ => Some(0),
+            };
+            // Skipping the song in the prompt skips it for good
+            let Some((score, result)) = chosen.and_then(|i| results.into_iter().nth(i)) else {
+                break;
+            };
+            debug!(
+                "Chose {} / {} for {} ({:.0}% match): {}",
+                result.title,
+                result.artist,
+                song,
+                score * 100.0,
+                result.url
+            );
+            return Ok(Some((source, result.url)));
+        }
+        Ok(None)
+    }
+
+    /// Downloads the lyric page at `url`, or takes it from the raw page cache unless `fresh`, and
+    /// extracts the lyric into `filename`.
+    fn download_lyric(
+        &self,
+        source: &dyn LyricSource,
+        url: &str,
+        song: &str,
+        filename: &Path,
+        fresh: bool,
+    ) -> Result<(), LyricError> {
+        let raw = self.raw_page(url);
+        let cached = raw.as_ref().and_then(|raw| fs::read_to_string(raw).ok());
+        let page = match (cached, &raw) {
+            (Some(page), Some(raw)) if !fresh => {
+                debug!(
+                    "Extracting lyric for {} from {} ({})",
+                    song,
+                    raw.display(),
+                    url
+                );
+                page
+            }
+            (cached, _) => {
+                info!("Downloading lyric for {} from {}", song, source.name());
+                debug!("Downloading {} for {}", url, song);
+                self.download_page(url, raw.as_deref(), cached)?
+            }
+        };
+        match self.save_lyric(source, url, &page, filename) {
+            Err(LyricError::NotFound) => {
+                warn!(
+                    "{} has no lyric, the song is probably gone from {}",
+                    url,
+                    source.name()
+                );
+                // Not to extract the not-found page again
+                if let Some(raw) = raw {
+                    let _ = fs::remove_file(&raw);
+                    let _ = fs::remove_file(raw.with_extension("json"));
+                }
+                Err(LyricError::NotFound)
+            }
+            result => result,
+        }
+    }
+
+    /// Downloads the page at `url`, unless it's not modified since it was cached at `raw`.
+    fn download_page(
+        &self,
+        url: &str,
+        raw: Option<&Path>,
+        cached: Option<String>,
+    ) -> Result<String, LyricError> {
+        let validators_file = raw.map(|raw| raw.with_extension("json"));
+        let validators = cached
+            .as_ref()
+            .and(validators_file.as_ref())
+            .and_then(|file| fs::read_to_string(file).ok())
+            .and_then(|json| serde_json::from_str::<Validators>(&json).ok())
+            .unwrap_or_default();
+        let mut request = self.http.client.get(url);
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        let response = self.http.send(request)?;
+        if let Some(page) = cached {
+            if response.status() == StatusCode::NOT_MODIFIED {
+                debug!("{} not modified since cached", url);
+                return Ok(page);
+            }
+        }
+
+        let header = |name| {
+            let value = response.headers().get(name)?.to_str().ok()?;
+            Some(value.to_string())
+        };
+        let validators = Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+        let page = self.http.text(response)?;
+        if let (true, Some(raw), Some(validators_file)) = (self.cache_raw, raw, validators_file) {
+            fs::create_dir_all(raw.parent().unwrap_or(Path::new(".")))?;
+            write_atomically(raw, &page)?;
+            if validators.etag.is_some() || validators.last_modified.is_some() {
+                let json = serde_json::to_string(&validators).map_err(io::Error::from)?;
+                write_atomically(&validators_file, json)?;
+            } else if validators_file.exists() {
+                fs::remove_file(&validators_file)?;
+            }
+        }
+        Ok(page)
+    }
+}
+
+/// What happened to a song fetched by `fetch_in_order`.
+pub enum Outcome {
+    Downloaded,
+    Cached,
+    NotFound,
+    /// Not cached while offline
+    Missing,
+}
+
+impl Outcome {
+    /// Whether the song has a lyric file to build.
+    pub fn is_built(&self) -> bool {
+        matches!(self, Outcome::Downloaded | Outcome::Cached)
+    }
+}
+
+/// Fetches every song into its lyric file, the one at its position in `filenames`, on up to
+/// `jobs` threads. Returns the songs with their lyric files and what `fetch` returned for them,
+/// in the songs order however the downloads finish, so the e-book keeps that order.
+pub fn fetch_in_order<R: Send>(
+    songs: &[String],
+    filenames: &[PathBuf],
+    jobs: usize,
+    fetch: impl Fn(&String, &Path) -> R + Sync,
+) -> Vec<(String, PathBuf, R)> {
+    let results = in_order(songs, jobs, |index, song| fetch(song, &filenames[index]));
+    songs
+        .iter()
+        .cloned()
+        .zip(filenames.iter().cloned())
+        .zip(results)
+        .map(|((song, filename), result)| (song, filename, result))
+        .collect()
+}
+
+/// Runs the job on every item on up to `jobs` threads, taking the items in order, and
+/// returns the results in the order of the items however long their jobs take.
+pub fn in_order<T: Sync, R: Send>(
+    items: &[T],
+    jobs: usize,
+    job: impl Fn(usize, &T) -> R + Sync,
+) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(items.iter().map(|_| None).collect::<Vec<_>>());
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(item) = items.get(index) else {
+                    break;
+                };
+                let result = job(index, item);
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+    let results = results.into_inner().unwrap();
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+/// Orders the search results by how similar they are to the song, best first, along with
+/// their similarity from 0 to 1. Results as similar keep the order of the site.
+pub fn rank_results(song: &str, results: Vec<SearchResult>) -> Vec<(f64, SearchResult)> {
+    let (title, artist) = split_song(song);
+    let (title, artist) = (collation_key(title), collation_key(artist));
+    let mut ranked = results
+        .into_iter()
+        .map(|result| {
+            let title_score = strsim::jaro_winkler(&title, &collation_key(&result.title));
+            let score = if artist.is_empty() {
+                title_score
+            } else {
+                let artist_score = strsim::jaro_winkler(&artist, &collation_key(&result.artist));
+                title_score * 0.7 + artist_score * 0.3
+            };
+            trace!(
+                "{} / {} matches {} by {:.0}%",
+                result.title,
+                result.artist,
+                song,
+                score * 100.0
+            );
+            (score, result)
+        })
+        .collect::<Vec<_>>();
+    ranked.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::RetryPolicy;
+    use crate::source::lyrics_translate::LyricsTranslate;
+    use crate::source::utaten::Utaten;
+    use httpmock::prelude::*;
+    use kuchiki::NodeRef;
+
+    const LYRIC: &str = include_str!("../tests/fixtures/utaten/lyric.html");
+    const SEARCH: &str = include_str!("../tests/fixtures/utaten/search.html");
+    const TRANSLATE_SEARCH: &str = include_str!("../tests/fixtures/lyrics_translate/search.html");
+    const TRANSLATE_SONG: &str = include_str!("../tests/fixtures/lyrics_translate/song.html");
+    const TRANSLATION: &str = include_str!("../tests/fixtures/lyrics_translate/translation.html");
+
+    fn downloader<'a>(utaten: &'a Utaten, cache: &Path) -> DownloaderBuilder<'a> {
+        let http = Http::new(RetryPolicy::NONE);
+        Downloader::builder(http)
+            .delay(Duration::ZERO)
+            .sources(vec![utaten])
+            .raw_dir(cache.join("raw"), true)
+    }
+
+    #[test]
+    fn cached_page_is_extracted_without_downloading() {
+        let server = MockServer::start();
+        let page = server.mock(|when, then| {
+            when.method(GET).path("/lyric/ok18091701/");
+            then.status(200).body(LYRIC);
+        });
+        let utaten = Utaten {
+            base_url: server.base_url(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let downloader = downloader(&utaten, dir.path()).build();
+        let url = server.url("/lyric/ok18091701/");
+        let filename = dir.path().join("曇天 - DOES.html");
+
+        assert!(downloader.fetch_song(&url, &filename, false).unwrap());
+        assert!(downloader.raw_page(&url).unwrap().exists());
+        fs::remove_file(&filename).unwrap();
+        assert!(downloader.fetch_song(&url, &filename, false).unwrap());
+        page.assert_hits(1);
+        assert!(filename.exists());
+    }
+
+    #[test]
+    fn unmodified_page_is_revalidated() {
+        let server = MockServer::start();
+        let mut changed = server.mock(|when, then| {
+            when.method(GET).path("/lyric/ok18091701/");
+            then.status(200).header("ETag", "\"v1\"").body(LYRIC);
+        });
+        let utaten = Utaten {
+            base_url: server.base_url(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let downloader = downloader(&utaten, dir.path()).build();
+        let url = server.url("/lyric/ok18091701/");
+        let filename = dir.path().join("曇天 - DOES.html");
+
+        downloader.fetch_song(&url, &filename, false).unwrap();
+        changed.assert_hits(1);
+        changed.delete();
+        let raw = downloader.raw_page(&url).unwrap();
+        assert!(raw.with_extension("json").exists());
+
+        let unmodified = server.mock(|when, then| {
+            when.method(GET)
+                .path("/lyric/ok18091701/")
+                .header("If-None-Match", "\"v1\"");
+            then.status(304);
+        });
+        fs::remove_file(&filename).unwrap();
+        downloader.fetch_song(&url, &filename, true).unwrap();
+        unmodified.assert_hits(1);
+        assert_eq!(fs::read_to_string(raw).unwrap(), LYRIC);
+        assert!(filename.exists());
+    }
+
+    #[test]
+    fn songs_found_before_are_not_searched_again() {
+        let server = MockServer::start();
+        let search = server.mock(|when, then| {
+            when.method(GET).path("/lyric/search");
+            then.status(200).body(SEARCH);
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/lyric/ok18091701/");
+            then.status(200).body(LYRIC);
+        });
+        let utaten = Utaten {
+            base_url: server.base_url(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let searches = dir.path().join("searches.json");
+        let filename = dir.path().join("曇天 - DOES.html");
+
+        let downloader = downloader(&utaten, dir.path()).build();
+        assert!(downloader
+            .fetch_song("曇天 / DOES", &filename, false)
+            .unwrap());
+        downloader.save_searches(&searches).unwrap();
+        let found = Downloader::read_searches(&searches);
+        assert_eq!(
+            found.get("曇天 / DOES"),
+            Some(&server.url("/lyric/ok18091701/"))
+        );
+
+        let downloader = self::downloader(&utaten, dir.path())
+            .searches(found)
+            .build();
+        assert!(downloader
+            .fetch_song("曇天 / DOES", &filename, true)
+            .unwrap());
+        search.assert_hits(1);
+    }
+
+    #[test]
+    fn forgotten_searches_are_searched_again() {
+        let utaten = Utaten::default();
+        let dir = tempfile::tempdir().unwrap();
+        let searches = BTreeMap::from([
+            (
+                "曇天 / DOES".to_string(),
+                "https://utaten.com/lyric/a/".to_string(),
+            ),
+            (
+                "修羅 / DOES".to_string(),
+                "https://utaten.com/lyric/b/".to_string(),
+            ),
+        ]);
+        let downloader = downloader(&utaten, dir.path()).searches(searches).build();
+
+        let forgotten = downloader.forget_searches(|_, url| url.ends_with("/a/"));
+        assert_eq!(forgotten, 1);
+        assert_eq!(downloader.searched("曇天 / DOES"), None);
+        assert_eq!(
+            downloader.searched("修羅 / DOES").as_deref(),
+            Some("https://utaten.com/lyric/b/")
+        );
+
+        let file = dir.path().join("searches.json");
+        downloader.save_searches(&file).unwrap();
+        assert_eq!(Downloader::read_searches(&file).len(), 1);
+    }
+
+    #[test]
+    fn translations_follow_the_lyrics() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/lyric/ok18091701/");
+            then.status(200).body(LYRIC);
+        });
+        let search = server.mock(|when, then| {
+            when.method(GET)
+                .path("/en/site-search")
+                .query_param("query", "曇天 DOES");
+            then.status(200).body(TRANSLATE_SEARCH);
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/en/does-donten-lyrics.html");
+            then.status(200).body(TRANSLATE_SONG);
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/en/donten-cloudy-sky.html");
+            then.status(200).body(TRANSLATION);
+        });
+        let utaten = Utaten {
+            base_url: server.base_url(),
+        };
+        let lyrics_translate = LyricsTranslate {
+            base_url: server.base_url(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let downloader = downloader(&utaten, dir.path())
+            .translations(vec![&lyrics_translate])
+            .build();
+        let url = server.url("/lyric/ok18091701/");
+        let filename = dir.path().join("曇天 - DOES.html");
+
+        assert!(downloader.fetch_song(&url, &filename, false).unwrap());
+        let document = kuchiki::parse_html().one(fs::read_to_string(&filename).unwrap());
+        let translation = select_first(&document, ".lyricTranslation").unwrap();
+        assert!(translation.text_contents().contains("In the cloudy sky"));
+        // Following the lyric, which stays the same
+        let lyric = extract_lyric(&document).unwrap();
+        assert_eq!(lyric.lyrics, "曇天の空に\n鐘が鳴る\n\nここに居る");
+        search.assert_hits(1);
+
+        // Extracting the lyric again keeps the translation without searching it
+        assert!(downloader.refresh_lyric(&url, &filename).unwrap());
+        let document = kuchiki::parse_html().one(fs::read_to_string(&filename).unwrap());
+        assert!(select_first(&document, ".lyricTranslation").is_ok());
+        search.assert_hits(1);
+    }
+
+    #[test]
+    fn songs_without_translation_get_the_lyrics_alone() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/lyric/ok18091701/");
+            then.status(200).body(LYRIC);
+        });
+        let search = server.mock(|when, then| {
+            when.method(GET).path("/en/site-search");
+            then.status(200).body("<html><body></body></html>");
+        });
+        let utaten = Utaten {
+            base_url: server.base_url(),
+        };
+        let lyrics_translate = LyricsTranslate {
+            base_url: server.base_url(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let downloader = downloader(&utaten, dir.path())
+            .translations(vec![&lyrics_translate])
+            .build();
+        let url = server.url("/lyric/ok18091701/");
+        let filename = dir.path().join("曇天 - DOES.html");
+
+        assert!(downloader.fetch_song(&url, &filename, false).unwrap());
+        search.assert_hits(1);
+        let document = kuchiki::parse_html().one(fs::read_to_string(&filename).unwrap());
+        assert!(select_first(&document, ".lyricTranslation").is_err());
+        assert!(extract_lyric(&document).is_ok());
+    }
+
+    /// Finds every song other than "missing", slower the earlier it is in the songs.
+    struct SlowSource {
+        finished: Mutex<Vec<String>>,
+    }
+
+    impl LyricSource for SlowSource {
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+        fn lyric_url_prefix(&self) -> String {
+            "slow://lyric/".to_string()
+        }
+
+        fn search(
+            &self,
+            song: &str,
+            _http: &Http,
+            _throttle: &Throttle,
+            _max_pages: usize,
+        ) -> Result<Vec<SearchResult>, LyricError> {
+            let (title, artist) = split_song(song);
+            let delay = 60 - 10 * title.parse::<u64>().unwrap_or(5);
+            thread::sleep(Duration::from_millis(delay));
+            self.finished.lock().unwrap().push(song.to_string());
+            if artist == "missing" {
+                return Ok(Vec::new());
+            }
+            Ok(vec![SearchResult {
+                title: title.to_string(),
+                artist: artist.to_string(),
+                url: format!("slow://lyric/{}", title),
+            }])
+        }
+
+        fn extract(&self, page: &str, _options: &ExtractOptions) -> Result<NodeRef, LyricError> {
+            select_first(&kuchiki::parse_html().one(page), "article")
+        }
+    }
+
+    #[test]
+    fn songs_stay_in_order_however_they_finish() {
+        let source = SlowSource {
+            finished: Mutex::default(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let utaten = Utaten::default();
+        let downloader = downloader(&utaten, dir.path())
+            .sources(vec![&source])
+            .build();
+        let songs = ["0 / a", "1 / b", "2 / missing", "3 / c", "4 / d"].map(String::from);
+        fs::create_dir_all(dir.path().join("raw")).unwrap();
+        for song in &songs {
+            let title = split_song(song).0;
+            let raw = downloader.raw_page(&format!("slow://lyric/{}", title));
+            let page = format!(
+                "<article><h2 class=\"newLyricTitle\">{}</h2></article>",
+                title
+            );
+            fs::write(raw.unwrap(), page).unwrap();
+        }
+        let filenames = songs
+            .iter()
+            .map(|song| dir.path().join(format!("{}.html", split_song(song).0)))
+            .collect::<Vec<_>>();
+
+        let fetch = |song: &String, filename: &Path| {
+            if downloader.fetch_song(song, filename, false)? {
+                Ok::<_, LyricError>(Outcome::Downloaded)
+            } else {
+                Ok(Outcome::NotFound)
+            }
+        };
+        let fetched = fetch_in_order(&songs, &filenames, songs.len(), fetch);
+        let built = fetched
+            .iter()
+            .filter(|(_, _, outcome)| outcome.as_ref().is_ok_and(Outcome::is_built))
+            .map(|(_, filename, _)| filename.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(built, ["0.html", "1.html", "3.html", "4.html"]);
+        let finished = source.finished.into_inner().unwrap();
+        assert_ne!(finished, songs, "the songs should finish out of order");
+    }
+}
@@ -0,0 +1,200 @@
+//! Building EPUB e-books natively, without pandoc.
+
+use crate::index;
+use anyhow::Result;
+use chrono::{NaiveDate, NaiveTime};
+use epub_builder::{EpubBuilder, EpubContent, EpubVersion, ZipLibrary};
+use kuchiki::{traits::TendrilSink, NodeData, NodeRef};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// Metadata of a natively built e-book.
+pub struct Metadata<'a> {
+    pub title: &'a str,
+    pub author: Option<&'a str>,
+    pub lang: &'a str,
+    pub cover: Option<&'a Path>,
+    pub date: Option<NaiveDate>,
+}
+
+/// Stylesheet of the e-books, which refers to `ICON_FONT` as `/fonts/utIcon.ttf`.
+pub const DEFAULT_CSS: &str = include_str!("../styles.css");
+
+/// The font of the icons utaten marks the lyrics with.
+pub const ICON_FONT: &[u8] = include_bytes!("../utIcon.ttf");
+
+/// Elements serialized as `<name/>` rather than with a closing tag.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Builds an EPUB 3 e-book from the cached lyric files without pandoc, one chapter per song.
+pub fn build(
+    output: &Path,
+    lyrics: &[PathBuf],
+    metadata: &Metadata,
+    css: &str,
+    fonts: &[PathBuf],
+) -> Result<()> {
+    let mut builder = EpubBuilder::new(ZipLibrary::new()?)?;
+    builder.epub_version(EpubVersion::V30);
+    builder.set_title(metadata.title);
+    if let Some(author) = metadata.author {
+        builder.add_author(author);
+    }
+    builder.add_language(metadata.lang);
+    if let Some(date) = metadata.date {
+        builder.set_publication_date(date.and_time(NaiveTime::MIN).and_utc());
+    }
+
+    // The stylesheet refers to fonts from the root, pandoc style, but lives next to them here
+    builder.stylesheet(css.replace("url('/fonts/", "url('fonts/").as_bytes())?;
+    for font in fonts {
+        let name = font.file_name().unwrap_or_default().to_string_lossy();
+        let extension = font.extension().unwrap_or_default().to_ascii_lowercase();
+        let mime = match extension.to_str() {
+            Some("otf") => "font/otf",
+            Some("woff") => "font/woff",
+            Some("woff2") => "font/woff2",
+            _ => "font/ttf",
+        };
+        builder.add_resource(format!("fonts/{}", name), File::open(font)?, mime)?;
+    }
+    if let Some(cover) = metadata.cover {
+        let extension = cover
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let mime = match extension.as_str() {
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "svg" => "image/svg+xml",
+            "webp" => "image/webp",
+            _ => "image/png",
+        };
+        builder.add_cover_image(format!("cover.{}", extension), File::open(cover)?, mime)?;
+    }
+    builder.inline_toc();
+
+    let documents = lyrics
+        .iter()
+        .map(|lyric| Ok(kuchiki::parse_html().one(fs::read_to_string(lyric)?)))
+        .collect::<Result<Vec<_>>>()?;
+    // Links between songs point into the chapters of the songs
+    let mut chapters = HashMap::new();
+    for (i, document) in documents.iter().enumerate() {
+        for node in document.select("[id]").unwrap() {
+            if let Some(id) = node.attributes.borrow().get("id") {
+                chapters.insert(id.to_string(), chapter_file(i));
+            }
+        }
+    }
+
+    // Songs are nested under the artist sections of `--group-by-artist` if there are any
+    let mut grouped = false;
+    for (i, (lyric, document)) in lyrics.iter().zip(&documents).enumerate() {
+        // Every song is a chapter of its own, so page breaks are implicit
+        for page_break in document.select(".page-break").unwrap().collect::<Vec<_>>() {
+            page_break.as_node().detach();
+        }
+        let section = document.select_first(".artistHeading").is_ok();
+        grouped |= section;
+        let title = index::page_title(document, lyric);
+        let body = document.select_first("body").unwrap();
+        builder.add_content(
+            EpubContent::new(
+                chapter_file(i),
+                chapter(&title, body.as_node(), metadata.lang, &chapters).as_bytes(),
+            )
+            .title(title)
+            .level(if grouped && !section { 2 } else { 1 }),
+        )?;
+    }
+
+    builder.generate(File::create(output)?)?;
+    Ok(())
+}
+
+fn chapter_file(i: usize) -> String {
+    format!("song_{:04}.xhtml", i + 1)
+}
+
+/// Wraps the contents of `body` into an XHTML document in the language, pointing links to ids
+/// to the `chapters` having them.
+fn chapter(title: &str, body: &NodeRef, lang: &str, chapters: &HashMap<String, String>) -> String {
+    let mut xhtml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\" \
+        xml:lang=\"{lang}\" lang=\"{lang}\">\n\
+        <head>\n<title>{title}</title>\n\
+        <link rel=\"stylesheet\" type=\"text/css\" href=\"stylesheet.css\" />\n</head>\n<body>\n",
+        title = escape(title, false)
+    );
+    for child in body.children() {
+        write_xhtml(&child, &mut xhtml, chapters);
+    }
+    xhtml.push_str("\n</body>\n</html>\n");
+    xhtml
+}
+
+/// Serializes the node as XHTML, which EPUB readers parse as strict XML, unlike the HTML
+/// kuchiki writes.
+fn write_xhtml(node: &NodeRef, xhtml: &mut String, chapters: &HashMap<String, String>) {
+    match node.data() {
+        NodeData::Element(element) => {
+            let name = &*element.name.local;
+            xhtml.push('<');
+            xhtml.push_str(name);
+            for (attr_name, attr) in &element.attributes.borrow().map {
+                let attr_name = &*attr_name.local;
+                // Scripting attributes like `@click` are not valid XML names
+                if attr_name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':'))
+                {
+                    let chapter = (attr_name == "href")
+                        .then(|| chapters.get(attr.value.strip_prefix('#')?))
+                        .flatten();
+                    let value = match chapter {
+                        Some(chapter) => format!("{}{}", chapter, attr.value),
+                        None => attr.value.clone(),
+                    };
+                    xhtml.push_str(&format!(" {}=\"{}\"", attr_name, escape(&value, true)));
+                }
+            }
+            if VOID_ELEMENTS.contains(&name) {
+                xhtml.push_str(" />");
+            } else {
+                xhtml.push('>');
+                for child in node.children() {
+                    write_xhtml(&child, xhtml, chapters);
+                }
+                xhtml.push_str(&format!("</{}>", name));
+            }
+        }
+        NodeData::Text(text) => xhtml.push_str(&escape(&text.borrow(), false)),
+        NodeData::Document(_) | NodeData::DocumentFragment => {
+            for child in node.children() {
+                write_xhtml(&child, xhtml, chapters);
+            }
+        }
+        NodeData::Comment(_) | NodeData::ProcessingInstruction(_) | NodeData::Doctype(_) => {}
+    }
+}
+
+fn escape(text: &str, attribute: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' if attribute => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
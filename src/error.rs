@@ -0,0 +1,27 @@
+use std::io;
+use thiserror::Error;
+
+/// Why finding or extracting a lyric failed, telling apart what only fails the song from what
+/// fails every song.
+#[derive(Debug, Error)]
+pub enum LyricError {
+    /// The lyric site has no such page.
+    #[error("page not found")]
+    NotFound,
+    /// Requesting the page failed, even after retrying.
+    #[error(transparent)]
+    Network(#[from] reqwest::Error),
+    /// The page lacks the markup the lyric is extracted from, so the site's markup probably
+    /// changed.
+    #[error("could not find `{selector}`")]
+    Parse { selector: String },
+    /// The page has no usable lyrics, like the pages of instrumentals.
+    #[error("{0}")]
+    NoLyrics(&'static str),
+    /// The URL is not a lyric URL of the lyric sites.
+    #[error("no lyric site has the URL {0}")]
+    UnknownUrl(String),
+    /// Reading or writing a cached file failed.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
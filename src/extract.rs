@@ -0,0 +1,295 @@
+//! Reading lyrics out of the markup of the cached lyric files.
+
+use crate::error::LyricError;
+use html5ever::{interface::QualName, local_name, namespace_url, ns, LocalName};
+use kuchiki::{iter::NodeIterator, Attribute, ExpandedName, NodeRef};
+use serde::Serialize;
+use std::fmt;
+
+/// Elements of lyric pages that never belong into the e-book.
+const UNWANTED_ELEMENTS: &[&str] = &[
+    "script", "noscript", "iframe", "ins", "object", "embed", "style", "link", "form",
+];
+
+/// Classes of ad and share button containers, `adsbygoogle` being AdSense's.
+const AD_CLASSES: &[&str] = &[
+    "adsbygoogle",
+    "ad",
+    "ads",
+    "adArea",
+    "adBox",
+    "advertisement",
+    "snsBtn",
+    "shareButtons",
+];
+
+/// A lyric with its credits, as exported to JSON.
+#[derive(Serialize)]
+pub struct Lyric {
+    pub title: String,
+    pub artist: String,
+    pub lyricists: Vec<String>,
+    pub composers: Vec<String>,
+    pub lyrics: String,
+    pub url: Option<String>,
+    pub stats: Stats,
+}
+
+/// How much text a lyric has. Japanese isn't separated into words by spaces, so characters
+/// other than whitespace are counted instead of words.
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Stats {
+    pub characters: usize,
+    pub lines: usize,
+}
+
+impl Stats {
+    pub fn of(text: &str) -> Self {
+        Stats {
+            characters: text.chars().filter(|c| !c.is_whitespace()).count(),
+            lines: text.lines().filter(|line| !line.trim().is_empty()).count(),
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} characters, {} lines", self.characters, self.lines)
+    }
+}
+
+/// Which parts of the lyric page end up in the e-book.
+#[derive(Clone)]
+pub struct ExtractOptions {
+    pub keep_romaji: bool,
+    pub furigana: bool,
+    pub credits_heading: bool,
+    /// Language of the cached lyric files
+    pub lang: String,
+}
+
+impl Default for ExtractOptions {
+    /// The defaults of the command line tool: Japanese lyrics with their furigana, without
+    /// romaji or a credits heading.
+    fn default() -> Self {
+        ExtractOptions {
+            keep_romaji: false,
+            furigana: true,
+            credits_heading: false,
+            lang: "ja".to_string(),
+        }
+    }
+}
+
+/// Removes scripts, embeds, ads and tracking pixels from the lyric, along with inline styles
+/// loading remote resources and event handler attributes.
+pub fn sanitize(article: &NodeRef) {
+    let unwanted = article
+        .descendants()
+        .elements()
+        .filter(|element| {
+            let attributes = element.attributes.borrow();
+            let is_ad = attributes.get("class").is_some_and(|class| {
+                class
+                    .split_whitespace()
+                    .any(|class| AD_CLASSES.contains(&class))
+            }) || attributes
+                .get("id")
+                .is_some_and(|id| id.starts_with("div-gpt-ad") || id.starts_with("google_ads"));
+            let is_pixel = &*element.name.local == "img"
+                && ["width", "height"]
+                    .iter()
+                    .any(|size| matches!(attributes.get(*size), Some("0" | "1")));
+            UNWANTED_ELEMENTS.contains(&&*element.name.local) || is_ad || is_pixel
+        })
+        .collect::<Vec<_>>();
+    for element in unwanted {
+        element.as_node().detach();
+    }
+
+    for element in article.inclusive_descendants().elements() {
+        let mut attributes = element.attributes.borrow_mut();
+        attributes.map.retain(|name, attribute| {
+            let name = &*name.local;
+            let handler = name.starts_with("on");
+            let remote_style = name == "style" && attribute.value.contains("url(");
+            !handler && !remote_style
+        });
+    }
+}
+
+/// Extracts the lyric with its credits from a cached lyric file.
+pub fn extract_lyric(document: &NodeRef) -> Result<Lyric, LyricError> {
+    let text_of = |selector| {
+        document
+            .select_first(selector)
+            .map(|node| node.text_contents().trim().to_string())
+            .ok()
+    };
+    let article = select_first(document, "article")?;
+    let url = article
+        .as_element()
+        .unwrap()
+        .attributes
+        .borrow()
+        .get("data-url")
+        .map(str::to_string);
+    let lyrics = plain_text(&select_first(document, ".lyricBody")?);
+    Ok(Lyric {
+        title: text_of(".newLyricTitle").unwrap_or_default(),
+        artist: text_of(".newLyricWork__name").unwrap_or_default(),
+        lyricists: extract_credits(document, "作詞"),
+        composers: extract_credits(document, "作曲"),
+        stats: Stats::of(&lyrics),
+        lyrics,
+        url,
+    })
+}
+
+/// Returns the names credited with `role` like "作詞" in the lyric data, empty if the role is
+/// not credited. Several names are either linked one by one or listed with separators, but
+/// never split on "・" which is part of foreign names.
+pub fn extract_credits(document: &NodeRef, role: &str) -> Vec<String> {
+    let Some(title) = document
+        .select(".newLyricWork__title")
+        .unwrap()
+        .find(|title| title.text_contents().contains(role))
+    else {
+        return Vec::new();
+    };
+    let Some(body) = title.as_node().following_siblings().elements().next() else {
+        return Vec::new();
+    };
+    let links = body.as_node().select("a").unwrap().collect::<Vec<_>>();
+    let names = if links.len() > 1 {
+        links.iter().map(|link| link.text_contents()).collect()
+    } else {
+        body.text_contents()
+            .split(['、', ',', '，', '/', '／', '&', '＆'])
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    names
+        .iter()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Builds a `作詞：...　作曲：...` line crediting the lyricists and composers.
+pub fn credits_line(lyricists: &[String], composers: &[String]) -> Option<NodeRef> {
+    let credits = [("作詞", lyricists), ("作曲", composers)]
+        .iter()
+        .filter(|(_, names)| !names.is_empty())
+        .map(|(role, names)| format!("{}：{}", role, names.join("、")))
+        .collect::<Vec<_>>();
+    if credits.is_empty() {
+        return None;
+    }
+    let line = element("p", "lyricCredits");
+    line.append(NodeRef::new_text(credits.join("　")));
+    Some(line)
+}
+
+/// Returns the text of the lyric body, one lyric line per line, without furigana readings.
+pub fn plain_text(lyric_body: &NodeRef) -> String {
+    lyric_text(lyric_body, false)
+}
+
+/// Returns the text of the lyric body like `plain_text`, but with the furigana readings in
+/// parentheses after the text they read, like `曇天(どんてん)`.
+pub fn annotated_text(lyric_body: &NodeRef) -> String {
+    lyric_text(lyric_body, true)
+}
+
+fn lyric_text(lyric_body: &NodeRef, readings: bool) -> String {
+    fn collect(node: &NodeRef, text: &mut String, readings: bool) {
+        for child in node.children() {
+            if let Some(element) = child.as_element() {
+                let is_reading = &*element.name.local == "rt"
+                    || element
+                        .attributes
+                        .borrow()
+                        .get("class")
+                        .is_some_and(|class| class.split_whitespace().any(|c| c == "rt"));
+                match &*element.name.local {
+                    "br" => text.push('\n'),
+                    "rp" => {}
+                    _ if is_reading => {
+                        if readings {
+                            text.push('(');
+                            collect(&child, text, readings);
+                            text.push(')');
+                        }
+                    }
+                    "div" | "p" => {
+                        collect(&child, text, readings);
+                        text.push('\n');
+                    }
+                    _ => collect(&child, text, readings),
+                }
+            } else if let Some(node_text) = child.as_text() {
+                text.push_str(&node_text.borrow());
+            }
+        }
+    }
+
+    let mut text = String::new();
+    collect(lyric_body, &mut text, readings);
+    let lines = text.lines().map(str::trim).collect::<Vec<_>>();
+    lines.join("\n").trim().to_string()
+}
+
+/// Returns the first element matching `selector`, or an error naming the selector so markup
+/// changes on utaten are easy to report.
+pub fn select_first(node: &NodeRef, selector: &str) -> Result<NodeRef, LyricError> {
+    node.select_first(selector)
+        .map(|element| element.as_node().clone())
+        .map_err(|()| LyricError::Parse {
+            selector: selector.to_string(),
+        })
+}
+
+/// Creates an empty HTML element of the class.
+pub fn element(name: &str, class: &str) -> NodeRef {
+    NodeRef::new_element(
+        QualName::new(None, ns!(html), LocalName::from(name)),
+        [(
+            ExpandedName::new("", local_name!("class")),
+            Attribute {
+                prefix: None,
+                value: class.to_string(),
+            },
+        )],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kuchiki::traits::TendrilSink;
+
+    const CREDITS: &str = include_str!("../tests/fixtures/utaten/credits.html");
+
+    #[test]
+    fn extract_credits_of_lyric_data() {
+        let document = kuchiki::parse_html().one(CREDITS);
+        assert_eq!(
+            extract_credits(&document, "作詞"),
+            ["ジョン・スミス", "山田太郎"]
+        );
+        assert_eq!(
+            extract_credits(&document, "作曲"),
+            ["マイケル・ジャクソン", "佐藤花子", "Ann", "Bob"]
+        );
+        assert!(extract_credits(&document, "編曲").is_empty());
+    }
+
+    #[test]
+    fn credits_line_of_credited_roles() {
+        let lyricists = ["ジョン・スミス".to_string(), "山田太郎".to_string()];
+        let line = credits_line(&lyricists, &[]).unwrap();
+        assert_eq!(line.text_contents(), "作詞：ジョン・スミス、山田太郎");
+        assert!(credits_line(&[], &[]).is_none());
+    }
+}
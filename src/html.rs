@@ -0,0 +1,49 @@
+//! Building the lyrics into a single HTML page, for `--format html`.
+
+use anyhow::Result;
+use kuchiki::{traits::TendrilSink, NodeRef};
+use std::fs;
+use std::path::{Path, PathBuf};
+use utaten_epub::extract::{element, select_first};
+
+/// Builds a single HTML page from the lyric files linked by `index::link_pages`, with the
+/// stylesheet inlined.
+pub fn build(output: &Path, lyrics: &[PathBuf], title: &str, lang: &str, css: &str) -> Result<()> {
+    let document = kuchiki::parse_html().one(format!(
+        "<!DOCTYPE html>\n<html lang=\"{}\"><head><meta charset=\"utf-8\"><title></title>\
+         <style></style></head><body></body></html>",
+        lang
+    ));
+    select_first(&document, "title")?.append(NodeRef::new_text(title));
+    // The stylesheet refers to fonts from the root, pandoc style, but the font is expected next
+    // to the page here
+    let css = css.replace("url('/fonts/", "url('");
+    select_first(&document, "style")?.append(NodeRef::new_text(css));
+
+    let body = select_first(&document, "body")?;
+    let heading = element("h1", "bookTitle");
+    heading.append(NodeRef::new_text(title));
+    body.append(heading);
+    for (i, lyric) in lyrics.iter().enumerate() {
+        let html = fs::read_to_string(lyric)?;
+        let lyric_document = kuchiki::parse_html().one(html);
+        // Sections are page boundaries already
+        let page_breaks = lyric_document.select(".page-break").unwrap();
+        for page_break in page_breaks.collect::<Vec<_>>() {
+            page_break.as_node().detach();
+        }
+
+        let section = element("section", "song");
+        let lyric_body = select_first(&lyric_document, "body")?;
+        for child in lyric_body.children().collect::<Vec<_>>() {
+            section.append(child);
+        }
+        if i > 0 {
+            body.append(element("hr", "songSeparator"));
+        }
+        body.append(section);
+    }
+
+    fs::write(output, document.to_string())?;
+    Ok(())
+}
@@ -0,0 +1,195 @@
+//! Requests to the lyric sites, retried and throttled.
+
+use crate::error::LyricError;
+use log::{debug, warn};
+use reqwest::{
+    blocking::{Client, RequestBuilder, Response},
+    header::RETRY_AFTER,
+    StatusCode,
+};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The longest a failed request waits to be retried, however many times it failed or however
+/// long its `Retry-After` header asks.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How failed HTTP requests are retried.
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Never retries, failing on the first error, like tests against a mock server want.
+    pub const NONE: RetryPolicy = RetryPolicy {
+        retries: 0,
+        delay: Duration::ZERO,
+    };
+}
+
+/// The HTTP client shared by all requests, so connections are pooled and kept alive.
+pub struct Http {
+    pub client: Client,
+    pub retry: RetryPolicy,
+    /// Where to save every response as is, see `--dump-raw`
+    pub dump_dir: Option<PathBuf>,
+}
+
+impl Http {
+    /// A client with reqwest's defaults, retrying as `retry` says and dumping nothing.
+    pub fn new(retry: RetryPolicy) -> Self {
+        Http {
+            client: Client::new(),
+            retry,
+            dump_dir: None,
+        }
+    }
+
+    /// Reads the body of the response, saving it as is into the dump directory if there's one.
+    pub fn text(&self, response: Response) -> Result<String, LyricError> {
+        let url = response.url().to_string();
+        let body = response.text()?;
+        if let Some(dump_dir) = &self.dump_dir {
+            fs::create_dir_all(dump_dir)?;
+            let dump = dump_dir.join(url_filename(&url));
+            debug!("Dumping {} into {}", url, dump.display());
+            write_atomically(&dump, &body)?;
+        }
+        Ok(body)
+    }
+
+    /// Sends the request, retrying with exponential backoff on connection errors, timeouts and
+    /// 5xx/429 responses. Rate limited responses wait as long as their `Retry-After` header asks.
+    pub fn send(&self, request: RequestBuilder) -> Result<Response, LyricError> {
+        let retry = &self.retry;
+        let mut attempt = 0;
+        loop {
+            let result = request
+                .try_clone()
+                .expect("GET requests have no streaming body")
+                .send();
+            let (reason, retry_after) = match &result {
+                Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    (response.status().to_string(), retry_after(response))
+                }
+                Ok(response) if response.status().is_server_error() => {
+                    (response.status().to_string(), None)
+                }
+                Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+                    return Err(LyricError::NotFound);
+                }
+                Err(err) if err.is_connect() || err.is_timeout() || err.is_request() => {
+                    (err.to_string(), None)
+                }
+                _ => return Ok(result?.error_for_status()?),
+            };
+            if attempt >= retry.retries {
+                return Ok(result?.error_for_status()?);
+            }
+
+            let delay = retry_after
+                .unwrap_or_else(|| backoff(retry.delay, attempt))
+                .min(MAX_DELAY);
+            attempt += 1;
+            warn!(
+                "Request failed ({}), retrying in {}ms ({}/{})",
+                reason,
+                delay.as_millis(),
+                attempt,
+                retry.retries
+            );
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// Doubles the delay for every attempt, without overflowing however many retries there are.
+fn backoff(delay: Duration, attempt: u32) -> Duration {
+    let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+    delay.saturating_mul(factor).min(MAX_DELAY)
+}
+
+/// Parses the `Retry-After` header of a rate limited response, given either in seconds or
+/// as an HTTP-date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Keeps songs from being fetched more often than once per `delay`, across all download threads.
+pub struct Throttle {
+    delay: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    pub fn new(delay: Duration) -> Self {
+        Throttle {
+            delay,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Blocks until `delay` has passed since the previous call.
+    pub fn wait(&self) {
+        let mut last = self.last.lock().unwrap();
+        if let Some(last) = *last {
+            let elapsed = last.elapsed();
+            if elapsed < self.delay {
+                thread::sleep(self.delay - elapsed);
+            }
+        }
+        *last = Some(Instant::now());
+    }
+}
+
+/// Writes the file through a temporary one renamed into place, so an interrupted write never
+/// leaves a truncated file behind to be taken as a valid cache.
+pub fn write_atomically(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Names the file saving the page at `url` after the URL.
+pub fn url_filename(url: &str) -> String {
+    let url = url.split_once("://").map_or(url, |(_, url)| url);
+    let name = url
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    format!("{}.html", name.trim_end_matches('_'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_longest_delay() {
+        let delay = Duration::from_millis(500);
+        assert_eq!(backoff(delay, 0), delay);
+        assert_eq!(backoff(delay, 3), Duration::from_secs(4));
+        assert_eq!(backoff(delay, 10), MAX_DELAY);
+        // Past where the factor or the delay would overflow
+        assert_eq!(backoff(delay, 40), MAX_DELAY);
+        assert_eq!(backoff(Duration::MAX, 1), MAX_DELAY);
+    }
+}
@@ -0,0 +1,177 @@
+//! Laying out the lyric files for building, in order behind an index page.
+
+use crate::extract::{element, plain_text, select_first, Stats};
+use crate::http::write_atomically;
+use anyhow::Result;
+use html5ever::{interface::QualName, local_name, namespace_url, ns};
+use kuchiki::{traits::TendrilSink, NodeRef};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How the lyric files are laid out for building.
+pub struct Layout<'a> {
+    /// Note how much text the songs have under their titles
+    pub stats: bool,
+    /// Class of the divs breaking pages after every page, no page breaks if `None`
+    pub page_break: Option<&'a str>,
+    /// Keep the titles of the songs above their lyrics
+    pub titles: bool,
+    /// Keep the artists, lyricists and composers of the songs
+    pub credits: bool,
+}
+
+/// Copies the lyric files into `build_dir` in their final order, each linking to the songs
+/// before and after it, behind an index page linking to all of them. Returns the index and
+/// the copies, ready for building.
+pub fn link_pages(build_dir: &Path, lyrics: &[PathBuf], layout: &Layout) -> Result<Vec<PathBuf>> {
+    let documents = lyrics
+        .iter()
+        .map(|lyric| Ok(kuchiki::parse_html().one(fs::read_to_string(lyric)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let titles = documents
+        .iter()
+        .zip(lyrics)
+        .map(|(document, lyric)| page_title(document, lyric))
+        .collect::<Vec<_>>();
+    let sections = documents
+        .iter()
+        .map(|document| document.select_first(".artistHeading").is_ok())
+        .collect::<Vec<_>>();
+
+    // Copies of an earlier build may be left over, of songs not built anymore
+    if build_dir.exists() {
+        fs::remove_dir_all(build_dir)?;
+    }
+    fs::create_dir_all(build_dir)?;
+    let mut pages = vec![build_dir.join("index.html")];
+    let index = index(&titles, &sections);
+    set_page_breaks(&index, layout.page_break);
+    write_atomically(&pages[0], index.to_string())?;
+    for (i, document) in documents.iter().enumerate() {
+        let body = select_first(document, "body")?;
+        if let Some(page) = body.children().find(|child| child.as_element().is_some()) {
+            let attributes = &page.as_element().unwrap().attributes;
+            attributes.borrow_mut().insert("id", page_id(i));
+        }
+
+        if layout.stats {
+            if let Ok(title) = document.select_first(".newLyricTitle") {
+                let lyric_body = select_first(document, ".lyricBody")?;
+                let note = element("p", "lyricStats");
+                let stats = Stats::of(&plain_text(&lyric_body));
+                note.append(NodeRef::new_text(stats.to_string()));
+                title.as_node().insert_after(note);
+            }
+        }
+
+        let nav = element("p", "songNav");
+        if i > 0 {
+            let prev = link("songNav__prev", i - 1, &format!("← {}", titles[i - 1]));
+            nav.append(prev);
+        }
+        if i + 1 < titles.len() {
+            let next = link("songNav__next", i + 1, &format!("{} →", titles[i + 1]));
+            nav.append(next);
+        }
+        // Before the page break, so the links stay with the song
+        match document.select_first(".page-break") {
+            Ok(page_break) => page_break.as_node().insert_before(nav),
+            Err(()) => body.append(nav),
+        }
+
+        set_page_breaks(document, layout.page_break);
+        // Titles are kept in the head, so the page still has one in tables of contents
+        let head = select_first(document, "head")?;
+        let title = NodeRef::new_element(QualName::new(None, ns!(html), local_name!("title")), []);
+        title.append(NodeRef::new_text(titles[i].clone()));
+        head.append(title);
+        let mut removed = Vec::new();
+        if !layout.titles {
+            removed.push(".newLyricTitle");
+        }
+        if !layout.credits {
+            removed.extend([".lyricData", ".lyricCredits"]);
+        }
+        if !removed.is_empty() {
+            let parts = document.select(&removed.join(", ")).unwrap();
+            for part in parts.collect::<Vec<_>>() {
+                part.as_node().detach();
+            }
+        }
+
+        let page = build_dir.join(format!("{:04}.html", i + 1));
+        write_atomically(&page, document.to_string())?;
+        pages.push(page);
+    }
+    Ok(pages)
+}
+
+/// Title of a lyric file in tables of contents, the song title or artist of a section.
+pub fn page_title(document: &NodeRef, lyric: &Path) -> String {
+    if let Ok(title) = document.select_first("head title") {
+        return title.text_contents().trim().to_string();
+    }
+    document
+        .select_first(".newLyricTitle, .artistHeading, .indexHeading")
+        .map(|title| title.text_contents().trim().to_string())
+        .unwrap_or_else(|()| {
+            let stem = lyric.file_stem().unwrap_or_default();
+            stem.to_string_lossy().into_owned()
+        })
+}
+
+/// Lists the pages by their titles, songs nested under the artist sections of
+/// `--group-by-artist` if there are any.
+fn index(titles: &[String], sections: &[bool]) -> NodeRef {
+    let index = element("div", "index");
+    let heading = element("h1", "indexHeading");
+    heading.append(NodeRef::new_text("Index"));
+    index.append(heading);
+    let list = element("ol", "index__list");
+    index.append(list.clone());
+
+    let mut artist_list: Option<NodeRef> = None;
+    for (i, title) in titles.iter().enumerate() {
+        let is_artist = sections[i];
+        let entry = NodeRef::new_element(QualName::new(None, ns!(html), local_name!("li")), []);
+        entry.append(link("index__entry", i, title));
+        match &artist_list {
+            Some(songs) if !is_artist => songs.append(entry.clone()),
+            _ => list.append(entry.clone()),
+        }
+        if is_artist {
+            let songs = element("ol", "index__list");
+            entry.append(songs.clone());
+            artist_list = Some(songs);
+        }
+    }
+    index.append(element("div", "page-break"));
+    index
+}
+
+/// Gives the page breaks of the cached lyric files the class, or removes them without one.
+fn set_page_breaks(node: &NodeRef, class: Option<&str>) {
+    for page_break in node.select(".page-break").unwrap().collect::<Vec<_>>() {
+        match class {
+            Some(class) => {
+                let mut attributes = page_break.attributes.borrow_mut();
+                attributes.insert("class", class.to_string());
+            }
+            None => page_break.as_node().detach(),
+        }
+    }
+}
+
+fn page_id(i: usize) -> String {
+    format!("song-{}", i + 1)
+}
+
+fn link(class: &str, i: usize, text: &str) -> NodeRef {
+    let link = element("a", class);
+    let attributes = &link.as_element().unwrap().attributes;
+    attributes
+        .borrow_mut()
+        .insert("href", format!("#{}", page_id(i)));
+    link.append(NodeRef::new_text(text));
+    link
+}
@@ -0,0 +1,13 @@
+//! Downloading the lyrics of Japanese songs from lyric sites, extracting them into HTML files
+//! and building them into e-books. `downloader::Downloader` does all of it for other programs.
+
+pub mod book;
+pub mod downloader;
+pub mod epub;
+pub mod error;
+pub mod extract;
+pub mod http;
+pub mod index;
+pub mod pandoc;
+pub mod songs;
+pub mod source;
@@ -0,0 +1,326 @@
+//! Building the lyric files into an EPUB e-book with pandoc, and the pandoc metadata file it
+//! takes the title of the book from.
+
+use crate::book::cached_text;
+use anyhow::{bail, Result};
+use chrono::NaiveDate;
+use log::{error, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// How pandoc builds the e-book.
+pub struct Pandoc<'a> {
+    /// The pandoc program to run
+    pub program: &'a Path,
+    pub metadata_file: &'a Path,
+    /// Overrides the title of the metadata file
+    pub title: Option<&'a str>,
+    pub lang: &'a str,
+    pub date: NaiveDate,
+    pub css: &'a Path,
+    /// Fonts to embed, which the stylesheet refers to under `/fonts/`
+    pub fonts: &'a [PathBuf],
+    /// Passed on to pandoc after the others, overriding them
+    pub extra_args: &'a [String],
+    /// Builds without the lyric files pandoc can't read, instead of failing
+    pub skip_invalid: bool,
+}
+
+impl Pandoc<'_> {
+    /// Builds the e-book from the lyric files into `output`, credited to `author`. If pandoc
+    /// fails, the lyric files it can't read are logged.
+    pub fn build(
+        &self,
+        filenames: &[PathBuf],
+        output: &Path,
+        cover: Option<&Path>,
+        author: Option<&str>,
+    ) -> Result<()> {
+        let Err(err) = run(self.command(filenames, output, cover, author)) else {
+            return Ok(());
+        };
+        // Tell which lyric files broke the build, by reading them one by one
+        let (valid, invalid) = filenames
+            .iter()
+            .cloned()
+            .partition::<Vec<_>, _>(|filename| self.reads(filename));
+        if invalid.is_empty() {
+            return Err(err);
+        }
+        error!("{:#}", err);
+        error!("pandoc can't read these lyric files:");
+        for filename in &invalid {
+            match cached_text(filename, ".newLyricTitle")? {
+                Some(title) => error!("  {} ({})", filename.display(), title),
+                None => error!("  {}", filename.display()),
+            }
+        }
+        if !self.skip_invalid {
+            bail!("pandoc can't read {} lyric files", invalid.len());
+        }
+        warn!(
+            "Building without the {} lyric files pandoc can't read",
+            invalid.len()
+        );
+        run(self.command(&valid, output, cover, author))
+    }
+
+    /// The pandoc command building the EPUB from the lyric files into `output`, credited to
+    /// `author`.
+    pub fn command(
+        &self,
+        filenames: &[PathBuf],
+        output: &Path,
+        cover: Option<&Path>,
+        author: Option<&str>,
+    ) -> process::Command {
+        let mut pandoc = process::Command::new(self.program);
+        if let Some(cover) = cover {
+            pandoc.arg(format!("--epub-cover-image={}", cover.display()));
+        }
+        pandoc
+            .arg("--toc")
+            .arg(format!("--metadata-file={}", self.metadata_file.display()))
+            .arg(format!("--metadata=lang:{}", self.lang))
+            // What the flags give overrides the metadata file, which may have been generated
+            // without them, and so do the author and the date, as the file is shared by every
+            // book of `--split-by-artist` and every build
+            .args(
+                self.title
+                    .map(|title| format!("--metadata=title:{}", title)),
+            )
+            .args(author.map(|author| format!("--metadata=author:{}", author)))
+            .arg(format!("--metadata=date:{}", self.date))
+            .args(["-f", "html"])
+            .args(filenames)
+            .arg("--css")
+            .arg(self.css)
+            .args(
+                self.fonts
+                    .iter()
+                    .map(|font| format!("--epub-embed-font={}", font.display())),
+            )
+            .arg("-o")
+            .arg(output)
+            .args(self.extra_args);
+        pandoc
+    }
+
+    /// Whether pandoc can read the lyric file on its own.
+    fn reads(&self, filename: &Path) -> bool {
+        process::Command::new(self.program)
+            .args(["-f", "html", "-t", "plain"])
+            .arg(filename)
+            .output()
+            .is_ok_and(|output| output.status.success())
+    }
+}
+
+/// Runs pandoc, failing with what it printed to stderr unless it succeeds. Its warnings are
+/// logged otherwise.
+fn run(mut pandoc: process::Command) -> Result<()> {
+    let output = pandoc.stderr(process::Stdio::piped()).output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        bail!("pandoc {}:\n{}", output.status, stderr.trim_end());
+    }
+    for line in stderr.lines() {
+        warn!("pandoc: {}", line);
+    }
+    Ok(())
+}
+
+/// Returns the first line of `pandoc --version`, e.g. `pandoc 3.1.2`, or `None` if pandoc
+/// can't be run.
+pub fn version(pandoc: &Path) -> Option<String> {
+    let output = process::Command::new(pandoc)
+        .arg("--version")
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Some(stdout.lines().next().unwrap_or("pandoc").trim().to_string())
+}
+
+/// Writes a pandoc metadata file for the e-book. The author and the date are left out, pandoc
+/// gets the ones of every book on its command line.
+pub fn write_metadata(path: &Path, title: &str, lang: &str) -> Result<()> {
+    let metadata = format!("---\ntitle: {}\nlanguage: {}\n", yaml_string(title), lang);
+    fs::write(path, metadata)?;
+    Ok(())
+}
+
+/// Reads the title of the book from the pandoc metadata file, the `main` one if it lists
+/// several like the bundled one does. `None` if there's no such file or it has no title.
+pub fn metadata_title(path: &Path) -> Option<String> {
+    let metadata = fs::read_to_string(path).ok()?;
+    let mut lines = metadata
+        .lines()
+        .skip_while(|line| !line.starts_with("title:"));
+    let title = lines.next()?["title:".len()..].trim();
+    if !title.is_empty() {
+        return Some(yaml_scalar(title));
+    }
+    // A list of titles, each with a `type` and a `text`
+    let mut titles = Vec::new();
+    for line in lines.take_while(|line| line.starts_with([' ', '-'])) {
+        let field = line.trim_start().trim_start_matches("- ");
+        if line.trim_start().starts_with("- ") {
+            titles.push((None, None));
+        }
+        let (Some((kind, text)), Some((key, value))) = (titles.last_mut(), field.split_once(':'))
+        else {
+            continue;
+        };
+        match key.trim() {
+            "type" => *kind = Some(yaml_scalar(value.trim())),
+            "text" => *text = Some(yaml_scalar(value.trim())),
+            _ => {}
+        }
+    }
+    let main = titles
+        .iter()
+        .find(|(kind, _)| kind.as_deref() == Some("main"));
+    main.or(titles.first())?.1.clone()
+}
+
+/// Unquotes a YAML scalar written in the flow style.
+fn yaml_scalar(value: &str) -> String {
+    if let Some(value) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        value.replace("\\\"", "\"").replace("\\\\", "\\")
+    } else if let Some(value) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        value.replace("''", "'")
+    } else {
+        value.to_string()
+    }
+}
+
+/// Quotes the value as a double-quoted YAML scalar.
+fn yaml_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pandoc<'a>(program: &'a Path, fonts: &'a [PathBuf]) -> Pandoc<'a> {
+        Pandoc {
+            program,
+            metadata_file: Path::new("lyrics.yaml"),
+            title: None,
+            lang: "ja",
+            date: NaiveDate::from_ymd_opt(2024, 3, 1).unwrap(),
+            css: Path::new("styles.css"),
+            fonts,
+            extra_args: &[],
+            skip_invalid: false,
+        }
+    }
+
+    #[test]
+    fn command_overrides_the_metadata_file() {
+        let fonts = [PathBuf::from("utIcon.ttf")];
+        let extra_args = ["--toc-depth=1".to_string()];
+        let pandoc = Pandoc {
+            title: Some("曇天"),
+            extra_args: &extra_args,
+            ..pandoc(Path::new("pandoc"), &fonts)
+        };
+        let command = pandoc.command(
+            &[PathBuf::from("a.html")],
+            Path::new("lyrics.epub"),
+            Some(Path::new("cover.png")),
+            Some("DOES"),
+        );
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            args,
+            [
+                "--epub-cover-image=cover.png",
+                "--toc",
+                "--metadata-file=lyrics.yaml",
+                "--metadata=lang:ja",
+                "--metadata=title:曇天",
+                "--metadata=author:DOES",
+                "--metadata=date:2024-03-01",
+                "-f",
+                "html",
+                "a.html",
+                "--css",
+                "styles.css",
+                "--epub-embed-font=utIcon.ttf",
+                "-o",
+                "lyrics.epub",
+                "--toc-depth=1",
+            ]
+        );
+    }
+
+    /// Stands in for pandoc, failing to read the lyric files with "broken" in them.
+    #[cfg(unix)]
+    fn fake_pandoc(dir: &Path) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let program = dir.join("pandoc");
+        fs::write(
+            &program,
+            "#!/bin/sh\nfor arg; do case $arg in *.html) ! grep -q broken \"$arg\" || exit 1;; esac; done\n",
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        program
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn build_skips_the_lyric_files_pandoc_cant_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let program = fake_pandoc(dir.path());
+        let good = dir.path().join("good.html");
+        let broken = dir.path().join("broken.html");
+        fs::write(&good, "<h2 class=\"newLyricTitle\">曇天</h2>").unwrap();
+        fs::write(&broken, "<h2 class=\"newLyricTitle\">broken</h2>").unwrap();
+        let filenames = [good, broken];
+        let output = dir.path().join("lyrics.epub");
+
+        let pandoc = pandoc(&program, &[]);
+        let err = pandoc.build(&filenames, &output, None, None).unwrap_err();
+        assert_eq!(err.to_string(), "pandoc can't read 1 lyric files");
+        let pandoc = Pandoc {
+            skip_invalid: true,
+            ..pandoc
+        };
+        pandoc.build(&filenames, &output, None, None).unwrap();
+    }
+
+    #[test]
+    fn metadata_title_of_the_generated_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lyrics.yaml");
+        assert_eq!(metadata_title(&path), None);
+        write_metadata(&path, "\"Lyrics\" \\ 歌詞", "ja").unwrap();
+        assert_eq!(metadata_title(&path).as_deref(), Some("\"Lyrics\" \\ 歌詞"));
+    }
+
+    #[test]
+    fn metadata_title_of_a_list_is_the_main_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lyrics.yaml");
+        fs::write(
+            &path,
+            "---\ntitle:\n- type: subtitle\n  text: 'Songs I''ve sung'\n- type: main\n  \
+             text: \"歌詞\"\nlanguage: ja\n",
+        )
+        .unwrap();
+        assert_eq!(metadata_title(&path).as_deref(), Some("歌詞"));
+
+        fs::write(&path, "---\ntitle:\n- text: 'I''m home'\n").unwrap();
+        assert_eq!(metadata_title(&path).as_deref(), Some("I'm home"));
+    }
+}
@@ -0,0 +1,430 @@
+//! Songs of the songs file, and the lyric files named after them.
+
+use crate::source;
+use anyhow::{bail, Result};
+use log::warn;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+/// Lines of the songs file starting with this stand for all songs of the artist after it.
+pub const ARTIST_PREFIX: &str = "artist:";
+
+/// Returns where the lyric of each song is cached. Songs whose names sanitize to the same
+/// filename get a ` (2)`, ` (3)`, ... suffix in songs file order, instead of overwriting each
+/// other.
+pub fn lyric_filenames(lyrics_dir: &Path, songs: &[String]) -> Vec<PathBuf> {
+    let mut taken = HashMap::new();
+    songs
+        .iter()
+        .map(|song| {
+            let name = lyric_name(song);
+            // Compare case-insensitively, as Windows and macOS filesystems do
+            let songs = taken.entry(name.to_lowercase()).or_insert_with(Vec::new);
+            let n = match songs.iter().position(|s| s == song) {
+                Some(i) => i + 1,
+                None => {
+                    songs.push(song.as_str());
+                    songs.len()
+                }
+            };
+            if n == 1 {
+                lyrics_dir.join(format!("{}.html", name))
+            } else {
+                lyrics_dir.join(format!("{} ({}).html", name, n))
+            }
+        })
+        .collect()
+}
+
+/// Returns whether `lyric_filenames` could have named the file, going by its name alone.
+pub fn is_lyric_filename(path: &Path) -> bool {
+    let Some(stem) = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".html"))
+    else {
+        return false;
+    };
+    // Without the ` (2)`, ` (3)`, ... suffix of songs named alike
+    let name = stem
+        .strip_suffix(')')
+        .and_then(|stem| stem.rsplit_once(" ("))
+        .filter(|(_, n)| n.parse::<usize>().is_ok_and(|n| n >= 2) && !n.starts_with('0'))
+        .map_or(stem, |(name, _)| name);
+    !name.is_empty() && sanitize_filename(name) == name
+}
+
+/// Returns the name the lyric of the song is cached under. Songs given as lyric URLs are named
+/// after the last path segment of the URL, e.g. `lyric/mi20011601/` is cached as `mi20011601`.
+pub fn lyric_name(song: &str) -> String {
+    match source::for_url(song).map(|source| &song[source.lyric_url_prefix().len()..]) {
+        Some(path) => path
+            .split(['?', '#'])
+            .next()
+            .unwrap_or_default()
+            .split('/')
+            .rfind(|segment| !segment.is_empty())
+            .map(sanitize_filename)
+            .unwrap_or_else(|| "lyric".to_string()),
+        // Named the same whether the spaces are typed as ideographic spaces or not
+        None => sanitize_filename(&song.replace('\u{3000}', " ").replace(" / ", " - ")),
+    }
+}
+
+/// Makes the name safe to use as a filename on Windows, macOS and Linux. Reserved characters
+/// are replaced by their full-width forms, so Japanese titles stay readable.
+pub fn sanitize_filename(name: &str) -> String {
+    let sanitized = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '/' => '／',
+            '\\' => '＼',
+            ':' => '：',
+            '*' => '＊',
+            '?' => '？',
+            '"' => '＂',
+            '<' => '＜',
+            '>' => '＞',
+            '|' => '｜',
+            c => c,
+        })
+        .collect::<String>();
+
+    // Windows drops trailing dots and spaces, and a leading dot hides the file elsewhere
+    let mut sanitized = sanitized
+        .trim_end_matches(['.', ' '])
+        .trim_start_matches('.')
+        .to_string();
+
+    // Leave room for the extension and a disambiguating suffix
+    while sanitized.len() > 200 {
+        sanitized.pop();
+    }
+
+    let stem = sanitized
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+    let reserved = ["CON", "PRN", "AUX", "NUL"].contains(&stem.as_str())
+        || matches!(stem.as_bytes(), [b'C', b'O', b'M', n] | [b'L', b'P', b'T', n] if n.is_ascii_digit());
+    if sanitized.is_empty() || reserved {
+        sanitized.push('_');
+    }
+    sanitized
+}
+
+/// Returns the key to sort Japanese text by, which folds katakana into hiragana, full-width
+/// alphanumerics into ASCII and ignores case, so e.g. "ハナ", "はな" and "hana" sort as
+/// expected. Kanji still sort by code point as there is no reading to sort them by.
+pub fn collation_key(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            'ァ'..='ヶ' => char::from_u32(c as u32 - 0x60).unwrap(),
+            '！'..='～' => char::from_u32(c as u32 - 0xFEE0).unwrap(),
+            c => c,
+        })
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Returns the song on a line of the songs file, `None` for blank lines and `#` comments.
+/// Notepad may save the file with a BOM and CRLF line endings, neither belongs to the song.
+pub fn parse_song_line(line: &str) -> Option<String> {
+    let line = line.trim_start_matches('\u{feff}').trim();
+    if line.is_empty() || line.starts_with('#') {
+        None
+    } else {
+        Some(canonical_song(line))
+    }
+}
+
+/// Writes the song the way it's meant, as `title / artist` with the whitespace of either
+/// collapsed, so songs typed with sloppy spacing around the slash are cached and searched the
+/// same. Lyric URLs and `artist:` lines are kept as they are.
+pub fn canonical_song(song: &str) -> String {
+    if song.starts_with(ARTIST_PREFIX) || source::for_url(song).is_some() {
+        return song.to_string();
+    }
+    let collapse = |part: &str| part.split_whitespace().collect::<Vec<_>>().join(" ");
+    let (title, artist) = split_song(song);
+    let title = collapse(title);
+    match artist {
+        // Otherwise the last slash of the title would count as the separator
+        "" if !split_song(&title).1.is_empty() => format!("{} /", title),
+        "" => title,
+        artist if title.is_empty() => format!("/ {}", collapse(artist)),
+        artist => format!("{} / {}", title, collapse(artist)),
+    }
+}
+
+/// Returns the songs of a CSV or TSV songs file, with columns for the title, the artist and
+/// the lyric URL to skip searching, the last two of which may be empty or left out. A header
+/// row starting with `title` is skipped, like blank rows and rows starting with `#`.
+pub fn parse_song_table(text: &str, delimiter: u8) -> Result<Vec<String>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .flexible(true)
+        .comment(Some(b'#'))
+        .from_reader(text.as_bytes());
+    let mut songs = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let line = record.position().map_or(0, |position| position.line());
+        let fields = record.iter().map(str::trim).collect::<Vec<_>>();
+        let (title, artist, url) = match fields[..] {
+            [title] => (title, "", ""),
+            [title, artist] => (title, artist, ""),
+            [title, artist, url] => (title, artist, url),
+            _ => bail!(
+                "line {}: {} columns, expected the title, artist and URL",
+                line,
+                fields.len()
+            ),
+        };
+        if i == 0 && title.eq_ignore_ascii_case("title") {
+            continue;
+        }
+        let song = match (title, artist, url) {
+            ("", "", "") => continue,
+            (_, _, "") if title.is_empty() => bail!("line {}: no title", line),
+            // A slash with spaces around it in the title is not followed by the artist
+            (_, "", "") => canonical_song(&format!("{} /", title)),
+            (_, _, "") => canonical_song(&format!("{} / {}", title, artist)),
+            _ if source::for_url(url).is_none() => {
+                bail!(
+                    "line {}: {} is not a lyric URL of the lyric sites",
+                    line,
+                    url
+                )
+            }
+            _ => url.to_string(),
+        };
+        songs.push(song);
+    }
+    Ok(songs)
+}
+
+/// Drops the songs listed again further down the songs file, warning about each of them.
+/// Songs listed again with the title and artist swapped are kept, but warned about too.
+pub fn dedupe_songs(songs: Vec<String>) -> Vec<String> {
+    let mut seen = HashMap::new();
+    songs
+        .into_iter()
+        .filter(|song| match seen.get(&song_key(song)) {
+            Some(first) => {
+                warn!("Skipping {}, it is listed already as {}", song, first);
+                false
+            }
+            None => {
+                let (title, artist) = split_song(song);
+                let swapped = song_key(&format!("{} / {}", artist, title));
+                if let Some(first) = seen.get(&swapped).filter(|_| !artist.is_empty()) {
+                    warn!(
+                        "{} may be {} with the title and artist swapped, the title goes first",
+                        song, first
+                    );
+                }
+                seen.insert(song_key(song), song.clone());
+                true
+            }
+        })
+        .collect()
+}
+
+/// Returns what songs listed the same way have in common, regardless of case, spacing and
+/// full-width or half-width characters.
+pub fn song_key(song: &str) -> String {
+    let folded = song
+        .chars()
+        .map(|c| match c {
+            '！'..='～' => char::from_u32(c as u32 - 0xFEE0).unwrap(),
+            '　' => ' ',
+            c => c,
+        })
+        .flat_map(char::to_lowercase)
+        .collect::<String>();
+    let (title, artist) = split_song(&folded);
+    let collapse = |part: &str| part.split_whitespace().collect::<Vec<_>>().join(" ");
+    format!("{} / {}", collapse(title), collapse(artist))
+}
+
+/// Splits the song into its title and the artist after ` / `, either of which may be empty.
+/// Bare slashes belong to the title, like in `AM11:00/HY`, so only the last slash with a space
+/// before or after it counts, as in `title /artist` too. The spaces may be ideographic spaces,
+/// as Japanese input methods type them.
+pub fn split_song(song: &str) -> (&str, &str) {
+    let song = song.trim();
+    let separator = song.rmatch_indices('/').map(|(i, _)| i).find(|&i| {
+        let before = song[..i].chars().next_back();
+        let after = song[i + 1..].chars().next();
+        before.is_some_and(char::is_whitespace) || after.is_some_and(char::is_whitespace)
+    });
+    match separator {
+        Some(i) => (song[..i].trim(), song[i + 1..].trim()),
+        None => (song, ""),
+    }
+}
+
+/// Normalizes the song for searching, so e.g. "ＡＢＣ　ｶﾞ" searches for "ABC ガ", by NFKC and
+/// collapsing whitespace.
+pub fn normalize_query(song: &str) -> String {
+    song.nfkc()
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_song_forms() {
+        assert_eq!(split_song("曇天 / DOES"), ("曇天", "DOES"));
+        assert_eq!(split_song("曇天/ DOES"), ("曇天", "DOES"));
+        assert_eq!(split_song("曇天 /DOES"), ("曇天", "DOES"));
+        assert_eq!(split_song("AM11:00/HY"), ("AM11:00/HY", ""));
+        assert_eq!(
+            split_song("1/3の純情な感情 / SIAM SHADE"),
+            ("1/3の純情な感情", "SIAM SHADE")
+        );
+        assert_eq!(split_song("A / B / C"), ("A / B", "C"));
+        assert_eq!(split_song("曇天"), ("曇天", ""));
+        assert_eq!(split_song("曇天 /"), ("曇天", ""));
+        assert_eq!(split_song("AM11:00/HY /"), ("AM11:00/HY", ""));
+        assert_eq!(split_song("/ DOES"), ("", "DOES"));
+        assert_eq!(split_song(" / DOES "), ("", "DOES"));
+        assert_eq!(split_song("曇天/"), ("曇天/", ""));
+    }
+
+    #[test]
+    fn canonical_song_forms() {
+        assert_eq!(canonical_song("  曇天   /   DOES "), "曇天 / DOES");
+        assert_eq!(canonical_song("曇天/DOES"), "曇天/DOES");
+        assert_eq!(canonical_song("曇天 /DOES"), "曇天 / DOES");
+        assert_eq!(canonical_song("Sweet  Memories"), "Sweet Memories");
+        assert_eq!(canonical_song("曇天 /"), "曇天");
+        // Still the title when the song is written back with the artist left out
+        assert_eq!(canonical_song("AM11:00 / HY /"), "AM11:00 / HY /");
+        assert_eq!(
+            split_song(&canonical_song("AM11:00 / HY /")),
+            ("AM11:00 / HY", "")
+        );
+        assert_eq!(canonical_song("/ DOES"), "/ DOES");
+        assert_eq!(canonical_song("artist:DOES"), "artist:DOES");
+        let url = "https://utaten.com/lyric/mi20011601/";
+        assert_eq!(canonical_song(url), url);
+    }
+
+    #[test]
+    fn sanitize_filename_of_reserved_names() {
+        assert_eq!(sanitize_filename("CON"), "CON_");
+        assert_eq!(sanitize_filename("nul.txt"), "nul.txt_");
+        assert_eq!(sanitize_filename("COM1"), "COM1_");
+        assert_eq!(sanitize_filename("lpt9"), "lpt9_");
+        assert_eq!(sanitize_filename("CONTACT"), "CONTACT");
+        assert_eq!(sanitize_filename("COM10"), "COM10");
+        assert_eq!(sanitize_filename("..."), "_");
+        assert_eq!(sanitize_filename(""), "_");
+    }
+
+    #[test]
+    fn sanitize_filename_uses_full_width_punctuation() {
+        assert_eq!(
+            sanitize_filename(r#"a/b\c:d*e?f"g<h>i|j"#),
+            "a／b＼c：d＊e？f＂g＜h＞i｜j"
+        );
+        // Full-width punctuation is already safe
+        assert_eq!(sanitize_filename("何で？／なんで："), "何で？／なんで：");
+        assert_eq!(sanitize_filename(".hidden. "), "hidden");
+        assert_eq!(sanitize_filename("tab\tnew\nline"), "tabnewline");
+    }
+
+    #[test]
+    fn lyric_filenames_of_songs_named_alike() {
+        let songs = [
+            "曇天 / DOES",
+            "曇天 - DOES",
+            "曇天 / DOES",
+            "Hana / A",
+            "HANA / a",
+            "曇天 ／ DOES",
+        ]
+        .map(String::from);
+        let names = lyric_filenames(Path::new("lyrics"), &songs)
+            .into_iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "曇天 - DOES.html",
+                "曇天 - DOES (2).html",
+                "曇天 - DOES.html",
+                "Hana - A.html",
+                "HANA - a (2).html",
+                "曇天 ／ DOES.html",
+            ]
+        );
+    }
+
+    #[test]
+    fn lyric_filenames_are_recognized() {
+        let songs = ["曇天 / DOES", "曇天 / DOES ", "曇天 - DOES", "CON"].map(String::from);
+        for filename in lyric_filenames(Path::new("lyrics"), &songs) {
+            assert!(is_lyric_filename(&filename), "{}", filename.display());
+        }
+        for name in [
+            "index.htm",
+            ".cover.html",
+            "CON.html",
+            "a:b.html",
+            "曇天 .html",
+            ".html",
+        ] {
+            assert!(!is_lyric_filename(Path::new(name)), "{}", name);
+        }
+        assert!(is_lyric_filename(Path::new("曇天 (2).html")));
+        assert!(is_lyric_filename(Path::new("曇天 (1).html")));
+    }
+
+    #[test]
+    fn normalize_query_folds_width_and_spaces() {
+        assert_eq!(normalize_query("ＡＢＣ　ｶﾞ"), "ABC ガ");
+        assert_eq!(normalize_query("  曇天 \t/  DOES\n"), "曇天 / DOES");
+        assert_eq!(normalize_query("曇天／ＤＯＥＳ"), "曇天/DOES");
+        assert_eq!(normalize_query("ﾊﾟﾌﾟﾘｶ"), "パプリカ");
+        assert_eq!(normalize_query("①"), "1");
+        assert_eq!(normalize_query("曇天"), "曇天");
+        assert_eq!(normalize_query("　"), "");
+    }
+
+    #[test]
+    fn ideographic_spaces_count_as_spaces() {
+        assert_eq!(split_song("曇天　/　DOES"), ("曇天", "DOES"));
+        assert_eq!(split_song("曇天　/DOES"), ("曇天", "DOES"));
+        assert_eq!(split_song("　曇天 /　DOES　"), ("曇天", "DOES"));
+        assert_eq!(lyric_name("曇天　/　DOES"), lyric_name("曇天 / DOES"));
+        assert_eq!(lyric_name("君の　名は / A"), "君の 名は - A");
+        assert_eq!(song_key("曇天　/　DOES"), song_key("曇天 / DOES"));
+        assert_eq!(song_key("君の　名は / A"), song_key("君の 名は / a"));
+    }
+
+    #[test]
+    fn parse_song_line_of_notepad_file() {
+        let file = "\u{feff}曇天 / DOES\r\n# 好きな曲\r\n\r\nAM11:00/HY\r\n  \r\n";
+        let songs = file.lines().filter_map(parse_song_line).collect::<Vec<_>>();
+        assert_eq!(songs, ["曇天 / DOES", "AM11:00/HY"]);
+        assert_eq!(parse_song_line("\u{feff}\r"), None);
+        assert_eq!(parse_song_line("\u{feff}# comment\r"), None);
+        assert_eq!(
+            parse_song_line("\u{feff}曇天 /DOES\r").as_deref(),
+            Some("曇天 / DOES")
+        );
+    }
+}
@@ -0,0 +1,413 @@
+//! Lyric sites to download the lyrics from.
+
+pub mod lyrics_translate;
+pub mod uta_net;
+pub mod utaten;
+
+use crate::error::LyricError;
+use crate::extract::ExtractOptions;
+use crate::http::{Http, Throttle};
+use anyhow::Result;
+use clap::ValueEnum;
+use html5ever::{interface::QualName, local_name, namespace_url, ns};
+use kuchiki::{iter::NodeIterator, traits::TendrilSink, Attribute, ExpandedName, NodeRef};
+use log::trace;
+use reqwest::blocking::RequestBuilder;
+use std::sync::LazyLock;
+
+/// A song found by searching a lyric site.
+pub struct SearchResult {
+    pub title: String,
+    pub artist: String,
+    pub url: String,
+}
+
+/// A lyric site, which finds songs and turns their lyric pages into the utaten flavored
+/// markup of the cached lyric files.
+pub trait LyricSource: Sync {
+    /// Name of the site in log messages.
+    fn name(&self) -> &'static str;
+
+    /// Prefix of the lyric page URLs, which the songs file can list to skip searching.
+    fn lyric_url_prefix(&self) -> String;
+
+    /// Searches for the song through up to `max_pages` pages of results, best match first.
+    fn search(
+        &self,
+        song: &str,
+        http: &Http,
+        throttle: &Throttle,
+        max_pages: usize,
+    ) -> Result<Vec<SearchResult>, LyricError>;
+
+    /// Lists up to `limit` songs of the artist, waiting for the throttle before every page of
+    /// them. Sites that can't list songs by artist find none.
+    fn discography(
+        &self,
+        _artist: &str,
+        _http: &Http,
+        _throttle: &Throttle,
+        _limit: usize,
+    ) -> Result<Vec<SearchResult>, LyricError> {
+        Ok(Vec::new())
+    }
+
+    /// Finds a translation of the song whose lyric page is at `url`, like its official English
+    /// lyrics, as an element with its lines. Sites without translations find none.
+    fn translation(
+        &self,
+        _url: &str,
+        _http: &Http,
+        _throttle: &Throttle,
+    ) -> Result<Option<NodeRef>, LyricError> {
+        Ok(None)
+    }
+
+    /// Extracts the downloaded lyric page into an `<article>` holding the `.newLyricTitle`,
+    /// `.lyricData` and `.lyricBody` of the song.
+    fn extract(&self, page: &str, options: &ExtractOptions) -> Result<NodeRef, LyricError>;
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Source {
+    /// https://utaten.com/
+    Utaten,
+    /// https://www.uta-net.com/
+    UtaNet,
+    /// https://lyricstranslate.com/, which has translations for `--include-translation`
+    LyricsTranslate,
+}
+
+static UTATEN: LazyLock<utaten::Utaten> = LazyLock::new(|| utaten::Utaten {
+    base_url: base_url("UTATEN_BASE_URL", utaten::BASE_URL),
+});
+static UTA_NET: LazyLock<uta_net::UtaNet> = LazyLock::new(|| uta_net::UtaNet {
+    base_url: base_url("UTA_NET_BASE_URL", uta_net::BASE_URL),
+});
+static LYRICS_TRANSLATE: LazyLock<lyrics_translate::LyricsTranslate> =
+    LazyLock::new(|| lyrics_translate::LyricsTranslate {
+        base_url: base_url("LYRICS_TRANSLATE_BASE_URL", lyrics_translate::BASE_URL),
+    });
+
+/// Returns the base URL the environment variable overrides the real site's with, like a mirror
+/// or a mock server.
+fn base_url(variable: &str, default: &str) -> String {
+    match std::env::var(variable) {
+        Ok(url) if !url.trim().is_empty() => url.trim().trim_end_matches('/').to_string(),
+        _ => default.to_string(),
+    }
+}
+
+impl Source {
+    /// The site at its base URL, the real site unless `UTATEN_BASE_URL`, `UTA_NET_BASE_URL` or
+    /// `LYRICS_TRANSLATE_BASE_URL` point elsewhere.
+    pub fn backend(self) -> &'static dyn LyricSource {
+        match self {
+            Source::Utaten => &*UTATEN,
+            Source::UtaNet => &*UTA_NET,
+            Source::LyricsTranslate => &*LYRICS_TRANSLATE,
+        }
+    }
+}
+
+/// Returns the site of the lyric page if the song is given by its URL.
+pub fn for_url(song: &str) -> Option<&'static dyn LyricSource> {
+    Source::value_variants()
+        .iter()
+        .map(|source| source.backend())
+        .find(|source| song.starts_with(&source.lyric_url_prefix()))
+}
+
+/// Collects the results of the page `request` gets and the pages after it, up to `max_pages`
+/// pages or `limit` results. Every page after the first waits for the throttle.
+fn walk_pages(
+    http: &Http,
+    throttle: &Throttle,
+    request: RequestBuilder,
+    max_pages: usize,
+    limit: usize,
+    parse_results: impl Fn(&NodeRef) -> Result<Vec<SearchResult>, LyricError>,
+    next_page: impl Fn(&NodeRef) -> Option<String>,
+) -> Result<Vec<SearchResult>, LyricError> {
+    let mut request = request;
+    let mut results = Vec::new();
+    for page in 1..=max_pages {
+        if page > 1 {
+            throttle.wait();
+        }
+        let document = kuchiki::parse_html().one(http.text(http.send(request)?)?);
+        let page_results = parse_results(&document)?;
+        if page_results.is_empty() {
+            break;
+        }
+        results.extend(page_results);
+        if results.len() >= limit {
+            results.truncate(limit);
+            break;
+        }
+        match next_page(&document) {
+            Some(url) => {
+                trace!("Next page of results: {}", url);
+                request = http.client.get(url);
+            }
+            None => break,
+        }
+    }
+    Ok(results)
+}
+
+/// URL the request goes to, for logging.
+fn request_url(request: &RequestBuilder) -> String {
+    request
+        .try_clone()
+        .and_then(|request| request.build().ok())
+        .map(|request| request.url().to_string())
+        .unwrap_or_default()
+}
+
+/// Query parameters that only track where visitors come from.
+const TRACKING_PARAMS: &[&str] = &[
+    "fbclid", "gclid", "yclid", "igshid", "mc_cid", "mc_eid", "ref", "ref_src",
+];
+
+/// Turns the link of a page on the site at `base_url` absolute, without tracking parameters.
+/// Links within the page like `#top` are left alone, and so are the hosts of links like
+/// `//host/path`.
+fn absolute_url(base_url: &str, href: &str) -> String {
+    if href.starts_with('#') {
+        return href.to_string();
+    }
+    let (href, fragment) = match href.find('#') {
+        Some(i) => href.split_at(i),
+        None => (href, ""),
+    };
+    let (path, query) = href.split_once('?').unwrap_or((href, ""));
+    let query = query
+        .split('&')
+        .filter(|param| {
+            let name = param.split('=').next().unwrap_or_default();
+            !param.is_empty() && !name.starts_with("utm_") && !TRACKING_PARAMS.contains(&name)
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    let mut url = if path.starts_with("//") {
+        // Protocol-relative, to another host maybe
+        let scheme = base_url
+            .split_once("://")
+            .map_or("https", |(scheme, _)| scheme);
+        format!("{}:{}", scheme, path)
+    } else if path.starts_with('/') {
+        format!("{}{}", base_url, path)
+    } else {
+        path.to_string()
+    };
+    if !query.is_empty() {
+        url.push('?');
+        url.push_str(&query);
+    }
+    url + fragment
+}
+
+/// Creates a link to the site's `path` with the text, or just the text without a path.
+fn link(base_url: &str, text: &str, path: Option<&str>) -> NodeRef {
+    let href = path.map(|path| {
+        let href = absolute_url(base_url, path);
+        (
+            ExpandedName::new("", local_name!("href")),
+            Attribute {
+                prefix: None,
+                value: href,
+            },
+        )
+    });
+    let link = NodeRef::new_element(QualName::new(None, ns!(html), local_name!("a")), href);
+    link.append(NodeRef::new_text(text.trim()));
+    link
+}
+
+fn is_blank(node: &NodeRef) -> bool {
+    node.text_contents().trim().is_empty()
+}
+
+/// Rewrites the lines of the lyric body so every line ends with a `<br>`, without the
+/// whitespace the page source has around them, and verses are separated by exactly one blank
+/// line. Without `<br>`s, the line breaks of the source are the lyric line breaks.
+fn normalize_lines(lyric_body: &NodeRef) {
+    let containers = lyric_body
+        .inclusive_descendants()
+        .elements()
+        .filter(|element| matches!(&*element.name.local, "div" | "p"))
+        .filter(|element| {
+            element.as_node().children().any(|child| {
+                is_br(&child)
+                    || child
+                        .as_text()
+                        .is_some_and(|text| !text.borrow().trim().is_empty())
+            })
+        })
+        .collect::<Vec<_>>();
+    for container in containers {
+        let container = container.as_node();
+        let has_br = container.children().any(|child| is_br(&child));
+
+        let mut lines = vec![Vec::new()];
+        for child in container.children() {
+            if is_br(&child) {
+                lines.push(Vec::new());
+            } else if let Some(text) = child.as_text() {
+                let text = text.borrow();
+                if has_br {
+                    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                    lines.last_mut().unwrap().push(NodeRef::new_text(text));
+                } else {
+                    for (i, segment) in text.split('\n').enumerate() {
+                        if i > 0 {
+                            lines.push(Vec::new());
+                        }
+                        lines.last_mut().unwrap().push(NodeRef::new_text(segment));
+                    }
+                }
+            } else {
+                lines.last_mut().unwrap().push(child);
+            }
+        }
+
+        container.children().for_each(|child| child.detach());
+        let mut blank = true;
+        let mut pending_blank = false;
+        for line in lines.iter().map(|line| trim_line(line)) {
+            if line.is_empty() {
+                pending_blank = !blank;
+                continue;
+            }
+            if pending_blank {
+                container.append(br());
+                pending_blank = false;
+            }
+            line.into_iter().for_each(|node| container.append(node));
+            container.append(br());
+            blank = false;
+        }
+    }
+}
+
+/// Returns the nodes of the line without leading and trailing whitespace, empty for blank
+/// lines.
+fn trim_line(line: &[NodeRef]) -> Vec<NodeRef> {
+    let text_of = |node: &NodeRef| node.as_text().map(|text| text.borrow().clone());
+    let Some(first) = line
+        .iter()
+        .position(|node| !is_blank(node) || node.as_text().is_none())
+    else {
+        return Vec::new();
+    };
+    let last = line
+        .iter()
+        .rposition(|node| !is_blank(node) || node.as_text().is_none())
+        .unwrap();
+    let mut trimmed = line[first..=last].to_vec();
+    if let Some(text) = text_of(&trimmed[0]) {
+        trimmed[0] = NodeRef::new_text(text.trim_start());
+    }
+    if let Some(text) = text_of(&trimmed[trimmed.len() - 1]) {
+        let last = trimmed.len() - 1;
+        trimmed[last] = NodeRef::new_text(text.trim_end());
+    }
+    trimmed
+}
+
+fn is_br(node: &NodeRef) -> bool {
+    node.as_element()
+        .is_some_and(|element| &*element.name.local == "br")
+}
+
+fn br() -> NodeRef {
+    NodeRef::new_element(QualName::new(None, ns!(html), local_name!("br")), [])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A lyric body of three verses, the way utaten's page source lays it out.
+    const VERSES: &str = "<div class=\"lyricBody\"><div class=\"medium\">
+        一番の<br>
+          歌詞  です<br>
+        <br>
+        <br>
+        <br>
+        二番の<br>歌詞<br><br>
+        <span class=\"ruby\">三番</span>   <br>
+        <br>
+    </div></div>";
+
+    fn normalized(html: &str) -> String {
+        let document = kuchiki::parse_html().one(html);
+        let lyric_body = document.select_first(".lyricBody").unwrap();
+        normalize_lines(lyric_body.as_node());
+        let medium = lyric_body.as_node().select_first(".medium").unwrap();
+        medium.as_node().children().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn absolute_url_of_links() {
+        let base_url = "https://utaten.com";
+        let absolute = |href| absolute_url(base_url, href);
+        assert_eq!(absolute("/artist/1854/"), "https://utaten.com/artist/1854/");
+        assert_eq!(absolute("#top"), "#top");
+        assert_eq!(absolute("https://example.com/a"), "https://example.com/a");
+        assert_eq!(
+            absolute("//cdn.utaten.com/img/a.png"),
+            "https://cdn.utaten.com/img/a.png"
+        );
+        assert_eq!(
+            absolute_url("http://127.0.0.1:8765", "//cdn.example.com/a"),
+            "http://cdn.example.com/a"
+        );
+    }
+
+    #[test]
+    fn absolute_url_without_tracking_params() {
+        let base_url = "https://utaten.com";
+        let absolute = |href| absolute_url(base_url, href);
+        assert_eq!(
+            absolute("/lyric/ok1/?utm_source=x&page=2&fbclid=y#lyric"),
+            "https://utaten.com/lyric/ok1/?page=2#lyric"
+        );
+        assert_eq!(
+            absolute("/lyric/ok1/?utm_medium=a&ref=b"),
+            "https://utaten.com/lyric/ok1/"
+        );
+        assert_eq!(
+            absolute("/search?title=a&&artist_name=b"),
+            "https://utaten.com/search?title=a&artist_name=b"
+        );
+        // Only whole parameter names are tracking ones
+        assert_eq!(
+            absolute("/a?referrer=1&prefer=2"),
+            "https://utaten.com/a?referrer=1&prefer=2"
+        );
+    }
+
+    #[test]
+    fn normalize_lines_keeps_one_blank_line_between_verses() {
+        assert_eq!(
+            normalized(VERSES),
+            "一番の<br>歌詞 です<br><br>二番の<br>歌詞<br><br>\
+             <span class=\"ruby\">三番</span><br>"
+        );
+    }
+
+    #[test]
+    fn normalize_lines_without_br_breaks_at_newlines() {
+        let html = "<div class=\"lyricBody\"><p>\n一番の\n歌詞\n\n\n\n二番の\n歌詞\n</p></div>";
+        let document = kuchiki::parse_html().one(html);
+        let lyric_body = document.select_first(".lyricBody").unwrap();
+        normalize_lines(lyric_body.as_node());
+        let paragraph = lyric_body.as_node().select_first("p").unwrap();
+        assert_eq!(
+            paragraph.as_node().to_string(),
+            "<p>一番の<br>歌詞<br><br>二番の<br>歌詞<br></p>"
+        );
+    }
+}
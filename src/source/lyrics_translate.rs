@@ -0,0 +1,291 @@
+//! https://lyricstranslate.com/, which has the original lyrics of songs along with their
+//! translations, like official English lyrics.
+
+use super::{
+    absolute_url, is_blank, link, normalize_lines, request_url, walk_pages, LyricSource,
+    SearchResult,
+};
+use crate::error::LyricError;
+use crate::extract::{element, select_first, ExtractOptions};
+use crate::http::{Http, Throttle};
+use anyhow::Result;
+use kuchiki::{traits::TendrilSink, NodeRef};
+use log::{debug, info};
+
+/// URL of the real site, without a trailing slash.
+pub const BASE_URL: &str = "https://lyricstranslate.com";
+
+/// Selects the link to the next page of search results.
+const NEXT_PAGE: &str = ".pager .pager-next a";
+
+/// Language of the translations to find, as the site names it in the list of translations.
+const LANGUAGE: &str = "English";
+
+pub struct LyricsTranslate {
+    /// URL of the site, without a trailing slash, which can be a mock server or a mirror.
+    pub base_url: String,
+}
+
+impl Default for LyricsTranslate {
+    fn default() -> Self {
+        LyricsTranslate {
+            base_url: BASE_URL.to_string(),
+        }
+    }
+}
+
+impl LyricSource for LyricsTranslate {
+    fn name(&self) -> &'static str {
+        "lyricstranslate"
+    }
+
+    fn lyric_url_prefix(&self) -> String {
+        format!("{}/en/", self.base_url)
+    }
+
+    fn search(
+        &self,
+        song: &str,
+        http: &Http,
+        throttle: &Throttle,
+        max_pages: usize,
+    ) -> Result<Vec<SearchResult>, LyricError> {
+        info!("Searching lyricstranslate for {}", song);
+        // Titles and artists are searched together
+        let query = song.replace(" / ", " ");
+        let request = http
+            .client
+            .get(format!("{}/en/site-search", self.base_url))
+            .query(&[("query", query.as_str())]);
+        debug!("Searching {} for {}", request_url(&request), song);
+        walk_pages(
+            http,
+            throttle,
+            request,
+            max_pages,
+            usize::MAX,
+            |document| parse_results(document, &self.base_url),
+            |document| next_page(document, &self.base_url),
+        )
+    }
+
+    fn translation(
+        &self,
+        url: &str,
+        http: &Http,
+        throttle: &Throttle,
+    ) -> Result<Option<NodeRef>, LyricError> {
+        let song_page = kuchiki::parse_html().one(http.text(http.send(http.client.get(url))?)?);
+        let Some(path) = translation_path(&song_page) else {
+            debug!("No {} translation of {}", LANGUAGE, url);
+            return Ok(None);
+        };
+        let translation_url = absolute_url(&self.base_url, &path);
+        debug!("Downloading translation {} of {}", translation_url, url);
+        throttle.wait();
+        let page = http.text(http.send(http.client.get(&translation_url))?)?;
+        let document = kuchiki::parse_html().one(page);
+        let lines = lines(&select_first(&document, "#translation-body")?);
+        if is_blank(&lines) {
+            return Ok(None);
+        }
+        Ok(Some(lines))
+    }
+
+    fn extract(&self, page: &str, _options: &ExtractOptions) -> Result<NodeRef, LyricError> {
+        let document = kuchiki::parse_html().one(page);
+        // Removed songs get a not-found page, without a 404 status
+        if document.select_first("#song-body").is_err() {
+            return Err(LyricError::NotFound);
+        }
+        let title = select_first(&document, "#song-body .title-h2")?;
+        let artist = select_first(&document, ".song-node-info-artist a")?;
+        let lyric = lines(&select_first(&document, "#song-body .ltf")?);
+        // Instrumental and placeholder entries have nothing in the body
+        if is_blank(&lyric) {
+            return Err(LyricError::NoLyrics("lyric body is empty"));
+        }
+
+        let lyric_title = element("h2", "newLyricTitle");
+        lyric_title.append(NodeRef::new_text(title.text_contents().trim()));
+
+        let attributes = artist.as_element().unwrap().attributes.borrow();
+        let artist_link = link(
+            &self.base_url,
+            &artist.text_contents(),
+            attributes.get("href"),
+        );
+        let name = element("dt", "newLyricWork__name");
+        name.append(artist_link);
+        let work = element("dl", "newLyricWork");
+        work.append(name);
+        let lyric_data = element("div", "lyricData");
+        lyric_data.append(work);
+
+        let lyric_body = element("div", "lyricBody");
+        lyric_body.append(lyric);
+
+        let article = element("article", "lyricArticle");
+        article.append(lyric_title);
+        article.append(lyric_data);
+        article.append(lyric_body);
+        Ok(article)
+    }
+}
+
+/// The path of the page of the song's translation into `LANGUAGE`, if it has one.
+fn translation_path(song_page: &NodeRef) -> Option<String> {
+    let link = song_page
+        .select(".song-list-translations-list a")
+        .unwrap()
+        .find(|link| link.text_contents().trim() == LANGUAGE)?;
+    let href = link.attributes.borrow().get("href")?.to_string();
+    Some(href)
+}
+
+/// Rearranges the stanzas of the site, a `.par` of a `<div>` per line each, into lines ending
+/// with `<br>`s, with a blank line between stanzas.
+fn lines(body: &NodeRef) -> NodeRef {
+    let medium = element("div", "medium");
+    for (i, stanza) in body.select(".par").unwrap().enumerate() {
+        if i > 0 {
+            medium.append(NodeRef::new_text("\n"));
+        }
+        // Selecting includes the stanza itself, so only its lines are taken from its children
+        for line in stanza
+            .as_node()
+            .children()
+            .filter(|child| child.as_element().is_some())
+        {
+            medium.append(NodeRef::new_text(format!(
+                "{}\n",
+                line.text_contents().trim()
+            )));
+        }
+    }
+    normalize_lines(&medium);
+    medium
+}
+
+fn next_page(document: &NodeRef, base_url: &str) -> Option<String> {
+    let link = document.select_first(NEXT_PAGE).ok()?;
+    let href = link.attributes.borrow().get("href")?.to_string();
+    Some(absolute_url(base_url, &href))
+}
+
+fn parse_results(document: &NodeRef, base_url: &str) -> Result<Vec<SearchResult>, LyricError> {
+    let results = document
+        .select(".ltsearch-results-line tr")
+        .unwrap()
+        // Rows of artists and translations have no song link
+        .filter_map(|row| {
+            let row = row.as_node();
+            let link = row.select_first(".ltsearch-songtitle a").ok()?;
+            let path = link.attributes.borrow().get("href")?.to_string();
+            let artist = row
+                .select_first(".ltsearch-songartist a")
+                .map(|artist| artist.text_contents().trim().to_string())
+                .unwrap_or_default();
+            Some(SearchResult {
+                title: link.text_contents().trim().to_string(),
+                artist,
+                url: absolute_url(base_url, &path),
+            })
+        })
+        .collect();
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::{extract_lyric, plain_text};
+    use crate::http::RetryPolicy;
+    use httpmock::prelude::*;
+    use std::time::Duration;
+
+    const SEARCH: &str = include_str!("../../tests/fixtures/lyrics_translate/search.html");
+    const SONG: &str = include_str!("../../tests/fixtures/lyrics_translate/song.html");
+    const TRANSLATION: &str =
+        include_str!("../../tests/fixtures/lyrics_translate/translation.html");
+
+    #[test]
+    fn parse_results_lists_only_songs() {
+        let document = kuchiki::parse_html().one(SEARCH);
+        let results = parse_results(&document, BASE_URL).unwrap();
+        let results = results
+            .iter()
+            .map(|result| (&*result.title, &*result.artist, &*result.url))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [
+                (
+                    "曇天",
+                    "DOES",
+                    "https://lyricstranslate.com/en/does-donten-lyrics.html"
+                ),
+                (
+                    "曇り",
+                    "Other",
+                    "https://lyricstranslate.com/en/other-kumori-lyrics.html"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn extract_rearranges_the_song_into_utaten_markup() {
+        let options = ExtractOptions::default();
+        let article = LyricsTranslate::default().extract(SONG, &options).unwrap();
+        let lyric = extract_lyric(&article).unwrap();
+        assert_eq!(lyric.title, "曇天");
+        assert_eq!(lyric.artist, "DOES");
+        assert_eq!(lyric.lyrics, "曇天の空に\n鐘が鳴る\n\nここに居る");
+        let link = article.select_first(".newLyricWork__name a").unwrap();
+        let href = link.attributes.borrow().get("href").map(str::to_string);
+        assert_eq!(
+            href.as_deref(),
+            Some("https://lyricstranslate.com/en/does-lyrics.html")
+        );
+    }
+
+    #[test]
+    fn translation_of_the_song_page() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/en/does-donten-lyrics.html");
+            then.status(200).body(SONG);
+        });
+        let translation = server.mock(|when, then| {
+            when.method(GET).path("/en/donten-cloudy-sky.html");
+            then.status(200).body(TRANSLATION);
+        });
+        let source = LyricsTranslate {
+            base_url: server.base_url(),
+        };
+        let http = Http::new(RetryPolicy::NONE);
+        let throttle = Throttle::new(Duration::ZERO);
+
+        let url = server.url("/en/does-donten-lyrics.html");
+        let lines = source.translation(&url, &http, &throttle).unwrap().unwrap();
+        translation.assert();
+        assert_eq!(
+            plain_text(&lines),
+            "In the cloudy sky\nthe bell rings\n\nI am here"
+        );
+
+        // Songs without an English translation have none
+        let page = SONG.replace(">English<", ">German<");
+        server.mock(|when, then| {
+            when.method(GET).path("/en/untranslated-lyrics.html");
+            then.status(200).body(page);
+        });
+        let url = server.url("/en/untranslated-lyrics.html");
+        assert!(source
+            .translation(&url, &http, &throttle)
+            .unwrap()
+            .is_none());
+        translation.assert_hits(1);
+    }
+}
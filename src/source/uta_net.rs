@@ -0,0 +1,308 @@
+//! https://www.uta-net.com/, which has no furigana or romaji, so lyric pages only need their
+//! title, credits and lines rearranged into utaten's markup.
+
+use super::{
+    absolute_url, is_blank, link, normalize_lines, request_url, walk_pages, LyricSource,
+    SearchResult,
+};
+use crate::error::LyricError;
+use crate::extract::{element, select_first, ExtractOptions};
+use crate::http::{Http, Throttle};
+use crate::songs::split_song;
+use anyhow::Result;
+use kuchiki::{traits::TendrilSink, NodeRef};
+use log::{debug, info};
+
+/// URL of the real site, without a trailing slash.
+pub const BASE_URL: &str = "https://www.uta-net.com";
+
+/// Selects the link to the next page of search results.
+const NEXT_PAGE: &str = ".pagination a[rel='next'], .pagination .next a";
+
+pub struct UtaNet {
+    /// URL of the site, without a trailing slash, which can be a mock server or a mirror.
+    pub base_url: String,
+}
+
+impl Default for UtaNet {
+    fn default() -> Self {
+        UtaNet {
+            base_url: BASE_URL.to_string(),
+        }
+    }
+}
+
+impl LyricSource for UtaNet {
+    fn name(&self) -> &'static str {
+        "uta-net"
+    }
+
+    fn lyric_url_prefix(&self) -> String {
+        format!("{}/song/", self.base_url)
+    }
+
+    fn search(
+        &self,
+        song: &str,
+        http: &Http,
+        throttle: &Throttle,
+        max_pages: usize,
+    ) -> Result<Vec<SearchResult>, LyricError> {
+        info!("Searching uta-net for {}", song);
+        let (title, artist) = split_song(song);
+        // Only one field can be searched at a time, so the artist is matched afterwards.
+        // `Aselect=2` searches song titles, `Bselect=3` for partial matches.
+        let request = http
+            .client
+            .get(format!("{}/search/", self.base_url))
+            .query(&[("Keyword", title), ("Aselect", "2"), ("Bselect", "3")]);
+        debug!("Searching {} for {}", request_url(&request), song);
+        let results = walk_pages(
+            http,
+            throttle,
+            request,
+            max_pages,
+            usize::MAX,
+            |document| parse_results(document, &self.base_url),
+            |document| next_page(document, &self.base_url),
+        )?;
+        let artist = artist.to_lowercase();
+        Ok(results
+            .into_iter()
+            .filter(|result| result.artist.to_lowercase().contains(&artist))
+            .collect())
+    }
+
+    fn extract(&self, page: &str, _options: &ExtractOptions) -> Result<NodeRef, LyricError> {
+        let document = kuchiki::parse_html().one(page);
+        // Stale lyric URLs get a not-found page, without a 404 status
+        if document.select_first(".kashi-title").is_err()
+            && document.select_first("#kashi_area").is_err()
+        {
+            return Err(LyricError::NotFound);
+        }
+        let title = select_first(&document, ".kashi-title")?;
+        let artist = select_first(&document, "[itemprop~='byArtist']")?;
+        let kashi = select_first(&document, "#kashi_area")?;
+        // Instrumental and placeholder entries have nothing in the body
+        if is_blank(&kashi) {
+            return Err(LyricError::NoLyrics("lyric body is empty"));
+        }
+
+        let lyric_title = element("h2", "newLyricTitle");
+        lyric_title.append(NodeRef::new_text(title.text_contents().trim()));
+
+        let work = element("dl", "newLyricWork");
+        let name = element("dt", "newLyricWork__name");
+        // The artist's name is inside the link to the artist page
+        let artist_path = artist
+            .ancestors()
+            .find_map(|node| {
+                let element = node.as_element()?;
+                let attributes = element.attributes.borrow();
+                (&*element.name.local == "a").then(|| attributes.get("href").map(str::to_string))
+            })
+            .flatten();
+        let artist_link = link(
+            &self.base_url,
+            &artist.text_contents(),
+            artist_path.as_deref(),
+        );
+        name.append(artist_link);
+        work.append(name);
+        for (role, itemprop) in [("作詞", "lyricist"), ("作曲", "composer")] {
+            let credits = document
+                .select(&format!("[itemprop='{}']", itemprop))
+                .unwrap()
+                .collect::<Vec<_>>();
+            if credits.is_empty() {
+                continue;
+            }
+            let credit_title = element("dt", "newLyricWork__title");
+            credit_title.append(NodeRef::new_text(role));
+            let credit_body = element("dd", "newLyricWork__body");
+            for (i, credit) in credits.iter().enumerate() {
+                if i > 0 {
+                    credit_body.append(NodeRef::new_text("、"));
+                }
+                let attributes = credit.attributes.borrow();
+                let link = link(
+                    &self.base_url,
+                    &credit.text_contents(),
+                    attributes.get("href"),
+                );
+                credit_body.append(link);
+            }
+            work.append(credit_title);
+            work.append(credit_body);
+        }
+        let lyric_data = element("div", "lyricData");
+        lyric_data.append(work);
+
+        let lyric_body = element("div", "lyricBody");
+        let medium = element("div", "medium");
+        kashi.children().for_each(|line| medium.append(line));
+        lyric_body.append(medium);
+        normalize_lines(&lyric_body);
+
+        let article = element("article", "lyricArticle");
+        article.append(lyric_title);
+        article.append(lyric_data);
+        article.append(lyric_body);
+        Ok(article)
+    }
+}
+
+fn next_page(document: &NodeRef, base_url: &str) -> Option<String> {
+    let link = document.select_first(NEXT_PAGE).ok()?;
+    let href = link.attributes.borrow().get("href")?.to_string();
+    Some(absolute_url(base_url, &href))
+}
+
+fn parse_results(document: &NodeRef, base_url: &str) -> Result<Vec<SearchResult>, LyricError> {
+    let results = document
+        .select(".songlist-table-body tr")
+        .unwrap()
+        // Rows without a song link are ads
+        .filter_map(|row| {
+            let row = row.as_node();
+            let text_of = |selector| {
+                row.select_first(selector)
+                    .map(|node| node.text_contents().trim().to_string())
+                    .ok()
+            };
+            let link = row.select_first("a[href^='/song/']").ok()?;
+            let path = link.attributes.borrow().get("href")?.to_string();
+            Some(SearchResult {
+                title: text_of(".songlist-title")
+                    .unwrap_or_else(|| link.text_contents().trim().to_string()),
+                artist: text_of("a[href^='/artist/']").unwrap_or_default(),
+                url: absolute_url(base_url, &path),
+            })
+        })
+        .collect();
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::extract_lyric;
+    use crate::http::RetryPolicy;
+    use httpmock::prelude::*;
+    use std::time::Duration;
+
+    const LYRIC: &str = include_str!("../../tests/fixtures/uta_net/lyric.html");
+    const NOT_FOUND: &str = include_str!("../../tests/fixtures/uta_net/not_found.html");
+    const SEARCH_PAGE_1: &str = include_str!("../../tests/fixtures/uta_net/search_page1.html");
+    const SEARCH_PAGE_2: &str = include_str!("../../tests/fixtures/uta_net/search_page2.html");
+
+    #[test]
+    fn extract_rearranges_the_lyric_into_utaten_markup() {
+        let uta_net = UtaNet::default();
+        let article = uta_net.extract(LYRIC, &ExtractOptions::default()).unwrap();
+        let lyric = extract_lyric(&article).unwrap();
+        assert_eq!(lyric.title, "曇天");
+        assert_eq!(lyric.artist, "DOES");
+        assert_eq!(lyric.lyricists, ["氏原ワタル"]);
+        assert_eq!(lyric.composers, ["氏原ワタル", "DOES"]);
+        assert_eq!(lyric.lyrics, "曇天の空に\n鐘が鳴る\n\nここに居る");
+        assert!(article.select_first("script").is_err());
+
+        let href = |selector| {
+            let link = article.select_first(selector).unwrap();
+            let href = link.attributes.borrow().get("href").map(str::to_string);
+            href
+        };
+        assert_eq!(
+            href(".newLyricWork__name a").as_deref(),
+            Some("https://www.uta-net.com/artist/10212/")
+        );
+        assert_eq!(
+            href(".newLyricWork__body a").as_deref(),
+            Some("https://www.uta-net.com/lyricist/12345/")
+        );
+    }
+
+    #[test]
+    fn parse_results_skips_ads() {
+        let document = kuchiki::parse_html().one(SEARCH_PAGE_1);
+        let results = parse_results(&document, BASE_URL).unwrap();
+        let results = results
+            .iter()
+            .map(|result| (&*result.title, &*result.artist, &*result.url))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [
+                ("曇天", "DOES", "https://www.uta-net.com/song/52167/"),
+                (
+                    "曇天",
+                    "ほかのアーティスト",
+                    "https://www.uta-net.com/song/99001/"
+                ),
+            ]
+        );
+        assert_eq!(
+            next_page(&document, BASE_URL).as_deref(),
+            Some("https://www.uta-net.com/search/?Keyword=%E6%9B%87%E5%A4%A9&pnum=2")
+        );
+        let document = kuchiki::parse_html().one(SEARCH_PAGE_2);
+        assert_eq!(next_page(&document, BASE_URL), None);
+    }
+
+    #[test]
+    fn search_walks_the_pages_and_keeps_the_artist() {
+        let server = MockServer::start();
+        let page_1 = server.mock(|when, then| {
+            when.method(GET)
+                .path("/search/")
+                .query_param("Keyword", "曇天")
+                .query_param("Aselect", "2")
+                .query_param("Bselect", "3");
+            then.status(200).body(SEARCH_PAGE_1);
+        });
+        let page_2 = server.mock(|when, then| {
+            when.method(GET)
+                .path("/search/")
+                .query_param("Keyword", "曇天")
+                .query_param("pnum", "2");
+            then.status(200).body(SEARCH_PAGE_2);
+        });
+        let uta_net = UtaNet {
+            base_url: server.base_url(),
+        };
+        let http = Http::new(RetryPolicy::NONE);
+        let throttle = Throttle::new(Duration::ZERO);
+        let found = |song, max_pages| {
+            let results = uta_net.search(song, &http, &throttle, max_pages).unwrap();
+            let results = results.into_iter().map(|result| result.title);
+            results.collect::<Vec<_>>()
+        };
+
+        assert_eq!(found("曇天 / does", 3), ["曇天", "曇天 (TV size)"]);
+        page_1.assert_hits(1);
+        page_2.assert_hits(1);
+        assert_eq!(found("曇天 / DOES", 1), ["曇天"]);
+        page_1.assert_hits(2);
+        page_2.assert_hits(1);
+        // Without an artist, every song of the title is
+        assert_eq!(found("曇天", 1), ["曇天", "曇天"]);
+    }
+
+    #[test]
+    fn extract_of_soft_404_is_not_found() {
+        let options = ExtractOptions::default();
+        let uta_net = UtaNet::default();
+        assert!(matches!(
+            uta_net.extract(NOT_FOUND, &options),
+            Err(LyricError::NotFound)
+        ));
+        // A lyric page missing only some of its parts has changed markup instead
+        let page = NOT_FOUND.replace("class=\"title\"", "class=\"kashi-title\"");
+        assert!(matches!(
+            uta_net.extract(&page, &options),
+            Err(LyricError::Parse { .. })
+        ));
+    }
+}
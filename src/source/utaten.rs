@@ -0,0 +1,439 @@
+//! https://utaten.com/, the site the cached lyric markup comes from.
+
+use super::{
+    absolute_url, is_blank, normalize_lines, request_url, walk_pages, LyricSource, SearchResult,
+};
+use crate::error::LyricError;
+use crate::extract::{select_first, ExtractOptions};
+use crate::http::{Http, Throttle};
+use crate::songs::split_song;
+use anyhow::Result;
+use html5ever::{interface::QualName, local_name, namespace_url, ns};
+use kuchiki::{iter::NodeIterator, traits::TendrilSink, NodeRef};
+use log::{debug, info};
+
+/// URL of the real site, without a trailing slash.
+pub const BASE_URL: &str = "https://utaten.com";
+
+/// Selects the link to the next page of search results.
+const NEXT_PAGE: &str = ".pager__next a, .pagination .next a, a[rel='next']";
+
+pub struct Utaten {
+    /// URL of the site, without a trailing slash, which can be a mock server or a mirror.
+    pub base_url: String,
+}
+
+impl Default for Utaten {
+    fn default() -> Self {
+        Utaten {
+            base_url: BASE_URL.to_string(),
+        }
+    }
+}
+
+impl LyricSource for Utaten {
+    fn name(&self) -> &'static str {
+        "utaten"
+    }
+
+    fn lyric_url_prefix(&self) -> String {
+        format!("{}/lyric/", self.base_url)
+    }
+
+    fn search(
+        &self,
+        song: &str,
+        http: &Http,
+        throttle: &Throttle,
+        max_pages: usize,
+    ) -> Result<Vec<SearchResult>, LyricError> {
+        info!("Searching utaten for {}", song);
+        let (title, artist) = split_song(song);
+        let request = http
+            .client
+            .get(search_url(&self.base_url))
+            .query(&[("artist_name", artist), ("title", title)]);
+        debug!("Searching {} for {}", request_url(&request), song);
+        walk_pages(
+            http,
+            throttle,
+            request,
+            max_pages,
+            usize::MAX,
+            |document| parse_results(document, &self.base_url),
+            |document| next_page(document, &self.base_url),
+        )
+    }
+
+    fn discography(
+        &self,
+        artist: &str,
+        http: &Http,
+        throttle: &Throttle,
+        limit: usize,
+    ) -> Result<Vec<SearchResult>, LyricError> {
+        info!("Listing songs of {} on utaten", artist);
+        let request = http
+            .client
+            .get(search_url(&self.base_url))
+            .query(&[("artist_name", artist), ("title", "")]);
+        throttle.wait();
+        walk_pages(
+            http,
+            throttle,
+            request,
+            usize::MAX,
+            limit,
+            |document| parse_results(document, &self.base_url),
+            |document| next_page(document, &self.base_url),
+        )
+    }
+
+    fn extract(&self, page: &str, options: &ExtractOptions) -> Result<NodeRef, LyricError> {
+        let document = kuchiki::parse_html().one(page);
+        // Stale lyric URLs get a not-found page, without a 404 status
+        if document.select_first(".newLyricTitle").is_err()
+            && document.select_first(".lyricBody").is_err()
+        {
+            return Err(LyricError::NotFound);
+        }
+        let lyric_title = extract_lyric_title(&document)?;
+        let lyric_data = extract_lyric_data(&document, &self.base_url)?;
+        let lyric_body = extract_lyric_body(&document, options)?;
+
+        let article = select_first(&document, "article")?;
+        article.children().for_each(|c| c.detach());
+        article.append(lyric_title);
+        article.append(lyric_data);
+        article.append(lyric_body);
+        Ok(article)
+    }
+}
+
+fn search_url(base_url: &str) -> String {
+    format!("{}/lyric/search", base_url)
+}
+
+fn next_page(document: &NodeRef, base_url: &str) -> Option<String> {
+    let link = document.select_first(NEXT_PAGE).ok()?;
+    let href = link.attributes.borrow().get("href")?.to_string();
+    if href.starts_with('?') {
+        Some(format!("{}{}", search_url(base_url), href))
+    } else {
+        Some(absolute_url(base_url, &href))
+    }
+}
+
+fn parse_results(document: &NodeRef, base_url: &str) -> Result<Vec<SearchResult>, LyricError> {
+    document
+        .select(".searchResult__title a")
+        .unwrap()
+        .map(|link| {
+            let attrs = link.attributes.borrow();
+            let path = attrs.get("href").ok_or_else(|| LyricError::Parse {
+                selector: ".searchResult__title a[href]".to_string(),
+            })?;
+            // The artist is listed next to the title, in the same result row
+            let artist = link
+                .as_node()
+                .ancestors()
+                .find_map(|row| row.select_first(".searchResult__name").ok())
+                .map(|name| name.text_contents().trim().to_string())
+                .unwrap_or_default();
+            Ok(SearchResult {
+                title: link.text_contents().trim().to_string(),
+                artist,
+                url: absolute_url(base_url, path),
+            })
+        })
+        .collect()
+}
+
+fn extract_lyric_title(document: &NodeRef) -> Result<NodeRef, LyricError> {
+    let lyric_title = select_first(document, ".newLyricTitle")?;
+    // Remove "の歌詞" in title, which some pages have without an element of its own
+    match lyric_title.select_first(".newLyricTitle_afterTxt") {
+        Ok(suffix) => suffix.as_node().detach(),
+        Err(()) => {
+            if let Some(text) = lyric_title.descendants().text_nodes().last() {
+                let title = text
+                    .borrow()
+                    .trim_end()
+                    .strip_suffix("の歌詞")
+                    .map(str::to_string);
+                if let Some(title) = title {
+                    *text.borrow_mut() = title;
+                }
+            }
+        }
+    }
+    Ok(lyric_title)
+}
+
+fn extract_lyric_data(document: &NodeRef, base_url: &str) -> Result<NodeRef, LyricError> {
+    let lyric_data = select_first(document, ".lyricData")?;
+    // # Remove tags and action buttons
+    select_first(&lyric_data, ".newLyricWorkFooter")?.detach();
+    // Fix relative links
+    lyric_data
+        .select(".newLyricWork a")
+        .unwrap()
+        .for_each(|link| {
+            let mut attrs = link.as_node().as_element().unwrap().attributes.borrow_mut();
+            if let Some(href) = attrs.get_mut("href") {
+                *href = absolute_url(base_url, href);
+            }
+        });
+    Ok(lyric_data)
+}
+
+fn extract_lyric_body(document: &NodeRef, options: &ExtractOptions) -> Result<NodeRef, LyricError> {
+    let lyric_body = select_first(document, ".lyricBody")?;
+    // Remove romaji part
+    if !options.keep_romaji {
+        if let Ok(romaji) = select_first(&lyric_body, ".romaji") {
+            romaji.detach();
+            if is_blank(&lyric_body) && !is_blank(&romaji) {
+                return Err(LyricError::NoLyrics("no non-romaji lyrics found"));
+            }
+        }
+    }
+    // Instrumental and placeholder entries have nothing in the body
+    if is_blank(&lyric_body) {
+        return Err(LyricError::NoLyrics("lyric body is empty"));
+    }
+    convert_furigana(&lyric_body, options.furigana);
+    normalize_lines(&lyric_body);
+    Ok(lyric_body)
+}
+
+/// Rewrites utaten's `span.ruby` furigana into `<ruby>`/`<rt>` markup, or strips the readings
+/// leaving only the base text.
+fn convert_furigana(lyric_body: &NodeRef, keep_readings: bool) {
+    let rubies = lyric_body.select("span.ruby").unwrap().collect::<Vec<_>>();
+    for ruby in rubies {
+        let ruby = ruby.as_node();
+        let text_of = |selector| {
+            ruby.select_first(selector)
+                .map(|node| node.text_contents())
+                .unwrap_or_default()
+        };
+        let base = NodeRef::new_text(text_of(".rb"));
+        if keep_readings {
+            let rt = NodeRef::new_element(QualName::new(None, ns!(html), local_name!("rt")), []);
+            rt.append(NodeRef::new_text(text_of(".rt")));
+            let replacement =
+                NodeRef::new_element(QualName::new(None, ns!(html), local_name!("ruby")), []);
+            replacement.append(base);
+            replacement.append(rt);
+            ruby.insert_before(replacement);
+        } else {
+            ruby.insert_before(base);
+        }
+        ruby.detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::{extract_lyric, plain_text};
+    use crate::http::RetryPolicy;
+    use httpmock::prelude::*;
+    use std::time::Duration;
+
+    const LYRIC: &str = include_str!("../../tests/fixtures/utaten/lyric.html");
+    const LYRIC_EMPTY: &str = include_str!("../../tests/fixtures/utaten/lyric_empty.html");
+    const LYRIC_ROMAJI: &str = include_str!("../../tests/fixtures/utaten/lyric_romaji.html");
+    const NOT_FOUND: &str = include_str!("../../tests/fixtures/utaten/not_found.html");
+    const SEARCH: &str = include_str!("../../tests/fixtures/utaten/search.html");
+    const SEARCH_EMPTY: &str = include_str!("../../tests/fixtures/utaten/search_empty.html");
+    const SEARCH_PAGE_1: &str = include_str!("../../tests/fixtures/utaten/search_page1.html");
+    const SEARCH_PAGE_2: &str = include_str!("../../tests/fixtures/utaten/search_page2.html");
+
+    #[test]
+    fn extract_keeps_only_the_lyric() {
+        let utaten = Utaten::default();
+        let article = utaten.extract(LYRIC, &ExtractOptions::default()).unwrap();
+        let lyric = extract_lyric(&article).unwrap();
+        assert_eq!(lyric.title, "曇天");
+        assert_eq!(lyric.artist, "DOES");
+        assert_eq!(lyric.lyricists, ["氏原ワタル"]);
+        assert_eq!(lyric.composers, ["氏原ワタル"]);
+        assert_eq!(lyric.lyrics, "曇天の空に\n鐘が鳴る\n\nここに居る");
+        assert!(article.select_first(".newLyricWorkFooter").is_err());
+        assert!(article.select_first(".lyricAd").is_err());
+        assert!(article.select_first("script").is_err());
+
+        let link = article.select_first(".newLyricWork__name a").unwrap();
+        let href = link.attributes.borrow().get("href").map(str::to_string);
+        assert_eq!(href.as_deref(), Some("https://utaten.com/artist/1854/"));
+    }
+
+    #[test]
+    fn parse_results_pairs_titles_with_artists() {
+        let document = kuchiki::parse_html().one(SEARCH);
+        let results = parse_results(&document, BASE_URL).unwrap();
+        let results = results
+            .iter()
+            .map(|result| (&*result.title, &*result.artist, &*result.url))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [
+                ("曇天", "DOES", "https://utaten.com/lyric/ok18091701/"),
+                (
+                    "曇天 (TV size)",
+                    "DOES",
+                    "https://utaten.com/lyric/ok18091702/"
+                ),
+                (
+                    "曇りのち晴れ",
+                    "ほかのアーティスト",
+                    "https://utaten.com/lyric/ok18091703/"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_results_of_empty_search() {
+        let document = kuchiki::parse_html().one(SEARCH_EMPTY);
+        assert!(parse_results(&document, BASE_URL).unwrap().is_empty());
+        assert_eq!(next_page(&document, BASE_URL), None);
+    }
+
+    fn lyric_body() -> NodeRef {
+        let document = kuchiki::parse_html().one(LYRIC);
+        select_first(&document, ".hiragana").unwrap()
+    }
+
+    #[test]
+    fn convert_furigana_to_ruby() {
+        let lyric_body = lyric_body();
+        convert_furigana(&lyric_body, true);
+        let html = lyric_body.to_string();
+        assert!(html.contains("<ruby>曇天<rt>どんてん</rt></ruby>の<ruby>空<rt>そら</rt></ruby>に"));
+        assert!(html.contains("ここに<ruby>居<rt>い</rt></ruby>る"));
+        assert!(lyric_body.select_first("span.ruby, .rb, .rt").is_err());
+    }
+
+    #[test]
+    fn convert_furigana_without_readings() {
+        let lyric_body = lyric_body();
+        convert_furigana(&lyric_body, false);
+        assert!(lyric_body.select_first("ruby, rt, span.ruby").is_err());
+        let text = lyric_body.text_contents();
+        let lines = text.lines().map(str::trim).collect::<Vec<_>>();
+        assert_eq!(lines, ["", "曇天の空に", "鐘が鳴る", "", "", "ここに居る"]);
+    }
+
+    #[test]
+    fn extract_lyric_body_of_empty_body() {
+        let document = kuchiki::parse_html().one(LYRIC_EMPTY);
+        assert!(matches!(
+            extract_lyric_body(&document, &ExtractOptions::default()),
+            Err(LyricError::NoLyrics("lyric body is empty"))
+        ));
+    }
+
+    #[test]
+    fn extract_lyric_body_of_romaji_only_body() {
+        let document = kuchiki::parse_html().one(LYRIC_ROMAJI);
+        assert!(matches!(
+            extract_lyric_body(&document, &ExtractOptions::default()),
+            Err(LyricError::NoLyrics("no non-romaji lyrics found"))
+        ));
+
+        let options = ExtractOptions {
+            keep_romaji: true,
+            ..ExtractOptions::default()
+        };
+        let document = kuchiki::parse_html().one(LYRIC_ROMAJI);
+        let lyric_body = extract_lyric_body(&document, &options).unwrap();
+        assert_eq!(plain_text(&lyric_body), "donten no sora ni\nkane ga naru");
+    }
+
+    #[test]
+    fn extract_of_soft_404_is_not_found() {
+        let utaten = Utaten::default();
+        assert!(matches!(
+            utaten.extract(NOT_FOUND, &ExtractOptions::default()),
+            Err(LyricError::NotFound)
+        ));
+        // A lyric page missing only some of its parts has changed markup instead
+        let page = LYRIC.replace("lyricBody", "lyricText");
+        assert!(matches!(
+            utaten.extract(&page, &ExtractOptions::default()),
+            Err(LyricError::Parse { selector }) if selector == ".lyricBody"
+        ));
+    }
+
+    fn lyric_title(page: &str) -> String {
+        let document = kuchiki::parse_html().one(page);
+        extract_lyric_title(&document).unwrap().text_contents()
+    }
+
+    #[test]
+    fn extract_lyric_title_without_suffix() {
+        assert_eq!(lyric_title(LYRIC), "曇天");
+        // Pages without an element of the suffix
+        let suffix = "<span class=\"newLyricTitle_afterTxt\">の歌詞</span>";
+        assert_eq!(lyric_title(&LYRIC.replace(suffix, "の歌詞\n")), "曇天");
+        let page = LYRIC
+            .replace(suffix, "")
+            .replace(">曇天<", "><b>曇天</b>の歌詞<");
+        assert_eq!(lyric_title(&page), "曇天");
+        // Only the suffix is removed
+        assert_eq!(lyric_title(&LYRIC.replace(suffix, "")), "曇天");
+        let page = LYRIC.replace(suffix, "").replace(">曇天<", ">僕の歌詞帳<");
+        assert_eq!(lyric_title(&page), "僕の歌詞帳");
+    }
+
+    #[test]
+    fn next_page_of_search_results() {
+        let document = kuchiki::parse_html().one(SEARCH_PAGE_1);
+        assert_eq!(
+            next_page(&document, BASE_URL).as_deref(),
+            Some("https://utaten.com/lyric/search?page=2&sort=popular_sort")
+        );
+        let document = kuchiki::parse_html().one(SEARCH_PAGE_2);
+        assert_eq!(next_page(&document, BASE_URL), None);
+    }
+
+    #[test]
+    fn search_walks_the_pages_of_results() {
+        let server = MockServer::start();
+        let page_1 = server.mock(|when, then| {
+            when.method(GET)
+                .path("/lyric/search")
+                .query_param("title", "曇天")
+                .query_param("artist_name", "DOES");
+            then.status(200).body(SEARCH_PAGE_1);
+        });
+        let page_2 = server.mock(|when, then| {
+            when.method(GET)
+                .path("/lyric/search")
+                .query_param("page", "2")
+                .query_param("sort", "popular_sort");
+            then.status(200).body(SEARCH_PAGE_2);
+        });
+        let utaten = Utaten {
+            base_url: server.base_url(),
+        };
+        let http = Http::new(RetryPolicy::NONE);
+        let throttle = Throttle::new(Duration::ZERO);
+        let titles = |max_pages| {
+            let results = utaten.search("曇天 / DOES", &http, &throttle, max_pages);
+            let results = results.unwrap().into_iter().map(|result| result.title);
+            results.collect::<Vec<_>>()
+        };
+
+        assert_eq!(titles(3), ["曇天", "曇天 (TV size)", "曇りのち晴れ"]);
+        page_1.assert_hits(1);
+        page_2.assert_hits(1);
+        assert_eq!(titles(1), ["曇天", "曇天 (TV size)"]);
+        page_1.assert_hits(2);
+        page_2.assert_hits(1);
+    }
+}
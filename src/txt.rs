@@ -0,0 +1,51 @@
+//! Building the lyrics into a plain text file, for `--format txt`.
+
+use anyhow::Result;
+use kuchiki::traits::TendrilSink;
+use std::fs;
+use std::path::{Path, PathBuf};
+use utaten_epub::extract::{annotated_text, extract_lyric, plain_text, select_first};
+
+/// Builds a plain text file from the lyric files linked by `index::link_pages`, each song
+/// headed by its title and credits. Furigana readings are kept in parentheses with
+/// `readings`, and dropped otherwise.
+pub fn build(output: &Path, lyrics: &[PathBuf], readings: bool) -> Result<()> {
+    let mut songs = Vec::new();
+    for lyric in lyrics {
+        let document = kuchiki::parse_html().one(fs::read_to_string(lyric)?);
+        if let Ok(heading) = document.select_first(".artistHeading") {
+            songs.push(format!("■ {}", heading.text_contents().trim()));
+            continue;
+        }
+        // The index page has no lyric
+        if document.select_first(".lyricBody").is_err() {
+            continue;
+        }
+        let lyric = extract_lyric(&document)?;
+        let mut header = Vec::new();
+        for line in [lyric.title, lyric.artist] {
+            if !line.is_empty() {
+                header.push(line);
+            }
+        }
+        for (role, names) in [("作詞", &lyric.lyricists), ("作曲", &lyric.composers)] {
+            if !names.is_empty() {
+                header.push(format!("{}：{}", role, names.join("、")));
+            }
+        }
+        let lyric_body = select_first(&document, ".lyricBody")?;
+        let text = if readings {
+            annotated_text(&lyric_body)
+        } else {
+            plain_text(&lyric_body)
+        };
+        if header.is_empty() {
+            songs.push(text);
+        } else {
+            songs.push(format!("{}\n\n{}", header.join("\n"), text));
+        }
+    }
+
+    fs::write(output, songs.join("\n\n\n") + "\n")?;
+    Ok(())
+}
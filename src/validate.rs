@@ -0,0 +1,84 @@
+//! Checking the built EPUB, for `--validate`.
+
+use anyhow::{Context, Result};
+use log::debug;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process;
+use zip::ZipArchive;
+
+/// Checks the EPUB with epubcheck, returning the problems it found. Without epubcheck, the
+/// EPUB is only checked to be an intact zip archive of well-formed XML documents.
+pub fn validate(epub: &Path, epubcheck: &Path) -> Result<Vec<String>> {
+    match process::Command::new(epubcheck).arg(epub).output() {
+        Ok(output) => {
+            let mut problems = Vec::new();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            for line in stdout.lines().chain(stderr.lines()) {
+                if line.starts_with("ERROR") || line.starts_with("FATAL") {
+                    problems.push(line.to_string());
+                }
+            }
+            if problems.is_empty() && !output.status.success() {
+                problems.push(format!("epubcheck failed with {}", output.status));
+            }
+            Ok(problems)
+        }
+        Err(err) => {
+            debug!(
+                "Checking {} without epubcheck, `{}` could not be run: {}",
+                epub.display(),
+                epubcheck.display(),
+                err
+            );
+            check_archive(epub)
+        }
+    }
+}
+
+/// Files that are parsed as XML to check them, judging by their extension.
+const XML_EXTENSIONS: &[&str] = &[".xhtml", ".html", ".opf", ".ncx", ".xml"];
+
+fn check_archive(epub: &Path) -> Result<Vec<String>> {
+    let mut archive = ZipArchive::new(File::open(epub)?)
+        .with_context(|| format!("{} is not a zip archive", epub.display()))?;
+    let mut problems = Vec::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let name = file.name().to_string();
+        // Reading the whole file checks it against its checksum too
+        let mut contents = Vec::new();
+        if let Err(err) = file.read_to_end(&mut contents) {
+            problems.push(format!("{}: {}", name, err));
+            continue;
+        }
+        if i == 0 && (name != "mimetype" || contents != b"application/epub+zip") {
+            problems.push("the first file is not the `mimetype` of an EPUB".to_string());
+        }
+        if !XML_EXTENSIONS
+            .iter()
+            .any(|extension| name.ends_with(extension))
+        {
+            continue;
+        }
+        let Ok(text) = String::from_utf8(contents) else {
+            problems.push(format!("{}: not UTF-8", name));
+            continue;
+        };
+        if let Err(err) = roxmltree::Document::parse_with_options(
+            &text,
+            roxmltree::ParsingOptions {
+                allow_dtd: true,
+                ..Default::default()
+            },
+        ) {
+            problems.push(format!("{}: {}", name, err));
+        }
+    }
+    if archive.by_name("META-INF/container.xml").is_err() {
+        problems.push("META-INF/container.xml is missing".to_string());
+    }
+    Ok(problems)
+}
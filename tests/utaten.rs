@@ -0,0 +1,91 @@
+//! Searching and downloading from a mock utaten, serving the pages in `fixtures/utaten`.
+
+use httpmock::prelude::*;
+use std::fs;
+use std::time::Duration;
+use utaten_epub::downloader::Downloader;
+use utaten_epub::http::{Http, RetryPolicy};
+use utaten_epub::source::utaten::Utaten;
+
+const LYRIC: &str = include_str!("fixtures/utaten/lyric.html");
+const SEARCH: &str = include_str!("fixtures/utaten/search.html");
+const SEARCH_EMPTY: &str = include_str!("fixtures/utaten/search_empty.html");
+const LYRIC_FILE: &str = include_str!("fixtures/utaten/lyric.expected.html");
+
+fn downloader(utaten: &Utaten) -> Downloader<'_> {
+    let http = Http::new(RetryPolicy::NONE);
+    Downloader::builder(http)
+        .delay(Duration::ZERO)
+        .sources(vec![utaten])
+        .build()
+}
+
+fn html(then: httpmock::Then, body: &str) {
+    then.status(200)
+        .header("Content-Type", "text/html; charset=UTF-8")
+        .body(body);
+}
+
+#[test]
+fn search_resolves_best_match() {
+    let server = MockServer::start();
+    let search = server.mock(|when, then| {
+        when.method(GET)
+            .path("/lyric/search")
+            .query_param("title", "曇天")
+            .query_param("artist_name", "DOES");
+        html(then, SEARCH);
+    });
+    let utaten = Utaten {
+        base_url: server.base_url(),
+    };
+
+    let url = downloader(&utaten).search("曇天", Some("DOES")).unwrap();
+    search.assert();
+    assert_eq!(url, Some(server.url("/lyric/ok18091701/")));
+}
+
+#[test]
+fn search_without_results_is_not_found() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path("/lyric/search");
+        html(then, SEARCH_EMPTY);
+    });
+    let utaten = Utaten {
+        base_url: server.base_url(),
+    };
+    let downloader = downloader(&utaten);
+
+    assert_eq!(downloader.search("曇天", Some("DOES")).unwrap(), None);
+    let dir = tempfile::tempdir().unwrap();
+    let filename = dir.path().join("曇天 - DOES.html");
+    let found = downloader.fetch_song("曇天 / DOES", &filename, false);
+    assert!(!found.unwrap());
+    assert!(!filename.exists());
+}
+
+#[test]
+fn download_writes_cleaned_lyric() {
+    let server = MockServer::start();
+    let page = server.mock(|when, then| {
+        when.method(GET).path("/lyric/ok18091701/");
+        html(then, LYRIC);
+    });
+    let utaten = Utaten {
+        base_url: server.base_url(),
+    };
+    let url = server.url("/lyric/ok18091701/");
+    let dir = tempfile::tempdir().unwrap();
+    let filename = dir.path().join("曇天 - DOES.html");
+
+    let lyric = downloader(&utaten).download(&url, &filename).unwrap();
+    page.assert();
+    assert_eq!(lyric.title, "曇天");
+    assert_eq!(lyric.artist, "DOES");
+    assert_eq!(lyric.lyricists, ["氏原ワタル"]);
+    assert_eq!(lyric.url.as_deref(), Some(&*url));
+    // The mock server's address changes from run to run
+    let written = fs::read_to_string(&filename).unwrap();
+    assert_eq!(written.replace(&server.base_url(), "BASE_URL"), LYRIC_FILE);
+}